@@ -0,0 +1,204 @@
+use super::collider::{
+    BoxCollider, CapsuleCollider, ColliderInfo, ColliderMarker, ColliderShape, CollisionLayers,
+    SphericalCollider,
+};
+use super::debug::{ObstacleInfo, ObstacleMarker};
+use crate::sets::*;
+use bevy::prelude::*;
+use bevy::render::primitives::Aabb;
+
+// rescales smaller than this are float noise from the transform propagation
+const FITTED_SCALE_EPSILON: f32 = 1e-4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AutoColliderKind {
+    Sphere,
+    Box,
+    // along the local z axis
+    Capsule,
+}
+
+// fits a collider to the meshes of the entity's scene once they have spawned,
+// and again whenever the entity gets rescaled. obstacles get their ObstacleInfo from it too
+#[derive(Component, Clone, Copy)]
+pub struct AutoCollider {
+    pub kind: AutoColliderKind,
+    // used when the entity has no ColliderInfo yet
+    pub layers: CollisionLayers,
+    fitted_scale: Option<Vec3>,
+}
+
+impl AutoCollider {
+    pub fn sphere(layers: CollisionLayers) -> Self {
+        Self {
+            kind: AutoColliderKind::Sphere,
+            layers,
+            fitted_scale: None,
+        }
+    }
+
+    pub fn cuboid(layers: CollisionLayers) -> Self {
+        Self {
+            kind: AutoColliderKind::Box,
+            layers,
+            fitted_scale: None,
+        }
+    }
+
+    pub fn capsule(layers: CollisionLayers) -> Self {
+        Self {
+            kind: AutoColliderKind::Capsule,
+            layers,
+            fitted_scale: None,
+        }
+    }
+}
+
+pub struct AutoColliderPlugin;
+impl Plugin for AutoColliderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, fit_auto_colliders.in_set(UpdateSet::InGame));
+    }
+}
+
+fn fit_auto_colliders(
+    mut commands: Commands,
+    mut query: Query<(
+        Entity,
+        &GlobalTransform,
+        &mut AutoCollider,
+        Option<&mut ColliderInfo>,
+        Has<ObstacleMarker>,
+    )>,
+    children_query: Query<&Children>,
+    mesh_query: Query<(&Aabb, &GlobalTransform)>,
+    scene_query: Query<(), With<SceneRoot>>,
+) {
+    for (entity, gt, mut auto, ci, obstacle) in query.iter_mut() {
+        let (scale, rotation, translation) = gt.to_scale_rotation_translation();
+        if auto
+            .fitted_scale
+            .is_some_and(|fitted| fitted.abs_diff_eq(scale, FITTED_SCALE_EPSILON))
+        {
+            continue;
+        }
+        // mesh bounds in the frame of the entity, scaled but not rotated
+        // since the collider takes its rotation from the transform
+        let to_local = rotation.inverse();
+        let mut radius: f32 = 0.;
+        let mut half_extents = Vec3::ZERO;
+        let mut found = false;
+        let mut stack = vec![entity];
+        while let Some(e) = stack.pop() {
+            if let Ok((aabb, mesh_gt)) = mesh_query.get(e) {
+                found = true;
+                let center = Vec3::from(aabb.center);
+                let half = Vec3::from(aabb.half_extents);
+                let local_center = to_local * (mesh_gt.transform_point(center) - translation);
+                let scaled_half = half * mesh_gt.scale();
+                // bounding sphere of the mesh box, reaching its corners
+                radius = radius.max(local_center.length() + scaled_half.length());
+                for i in 0..8 {
+                    let sign = Vec3::new(
+                        if i & 1 == 0 { -1. } else { 1. },
+                        if i & 2 == 0 { -1. } else { 1. },
+                        if i & 4 == 0 { -1. } else { 1. },
+                    );
+                    let corner =
+                        to_local * (mesh_gt.transform_point(center + half * sign) - translation);
+                    half_extents = half_extents.max(corner.abs());
+                }
+            }
+            if let Ok(children) = children_query.get(e) {
+                // attached scenes like turrets and launchers are not part of the hull
+                stack.extend(children.iter().filter(|c| !scene_query.contains(**c)));
+            }
+        }
+        // the scene has not spawned its meshes yet
+        if !found {
+            continue;
+        }
+
+        let shape = match auto.kind {
+            AutoColliderKind::Sphere => ColliderShape::Sphere(SphericalCollider {
+                radius,
+                center: translation,
+            }),
+            AutoColliderKind::Box => ColliderShape::Box(BoxCollider {
+                half_extents,
+                center: translation,
+                rotation,
+            }),
+            AutoColliderKind::Capsule => {
+                let radius = half_extents.x.max(half_extents.y);
+                ColliderShape::Capsule(CapsuleCollider {
+                    radius,
+                    half_length: (half_extents.z - radius).max(0.),
+                    center: translation,
+                    rotation,
+                })
+            }
+        };
+        if obstacle {
+            commands.entity(entity).insert(ObstacleInfo {
+                radius: shape.radius(),
+            });
+        }
+        if let Some(mut ci) = ci {
+            ci.shape = shape;
+        } else {
+            commands.entity(entity).insert((
+                ColliderInfo {
+                    shape,
+                    layers: auto.layers,
+                },
+                ColliderMarker,
+            ));
+        }
+        auto.fitted_scale = Some(scale);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    // an entity scaled by 2 with a single child mesh of half extents (1, 1, 2)
+    fn fitted(auto: AutoCollider) -> (ColliderShape, Option<f32>) {
+        let mut world = World::new();
+        let parent = Transform::from_xyz(1., 2., 3.).with_scale(Vec3::splat(2.));
+        let mesh = Aabb::from_min_max(Vec3::new(-1., -1., -2.), Vec3::new(1., 1., 2.));
+        let entity = world
+            .spawn((auto, GlobalTransform::from(parent), ObstacleMarker))
+            .with_children(|children| {
+                children.spawn((mesh, GlobalTransform::from(parent)));
+            })
+            .id();
+        world.run_system_once(fit_auto_colliders).unwrap();
+        let shape = world.get::<ColliderInfo>(entity).unwrap().shape;
+        let obstacle = world.get::<ObstacleInfo>(entity).map(|o| o.radius);
+        (shape, obstacle)
+    }
+
+    #[test]
+    fn sphere_reaches_the_corners_of_the_mesh() {
+        let (shape, obstacle) = fitted(AutoCollider::sphere(CollisionLayers::environment()));
+        let ColliderShape::Sphere(sphere) = shape else {
+            panic!("not a sphere");
+        };
+        assert!((sphere.radius - Vec3::new(2., 2., 4.).length()).abs() < 1e-4);
+        assert!(sphere.center.abs_diff_eq(Vec3::new(1., 2., 3.), 1e-4));
+        assert_eq!(obstacle, Some(sphere.radius));
+    }
+
+    #[test]
+    fn capsule_runs_along_z() {
+        let (shape, _) = fitted(AutoCollider::capsule(CollisionLayers::environment()));
+        let ColliderShape::Capsule(capsule) = shape else {
+            panic!("not a capsule");
+        };
+        assert!((capsule.radius - 2.).abs() < 1e-4);
+        assert!((capsule.half_length - 2.).abs() < 1e-4);
+    }
+}
@@ -1,13 +1,18 @@
+use super::auto_collider::AutoCollider;
 use super::camera::REAR_VIEW_LAYERS;
+use super::collider::CollisionDamage;
 use super::collider::*;
-use super::collider::{self, CollisionDamage};
+use super::damage::{Armor, DamageType, EntityDestroyed};
 use super::explosion::{ExplosibleObjectMarker, ExplosionEvent};
+use super::faction::{Faction, FactionTable};
 use super::missile::HomingMissileTarget;
+use super::movement::RigidBody;
 use super::spaceship::Health;
+use super::spatial_query::SpatialQuery;
 use crate::states::*;
 use bevy::prelude::*;
 use rand::Rng;
-use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use super::{turret::*, GameObjectMarker};
 use crate::asset_loader::*;
@@ -18,6 +23,8 @@ use crate::states::GameState;
 
 const SHOOT_VICINITY_ANGLE: f32 = 15.;
 const SHOOT_VICINITY_DISTANCE: f32 = 20.;
+const BOT_MASS: f32 = 1.;
+const BOT_DRAG: f32 = 2.;
 
 // marks bot entities
 #[derive(Component)]
@@ -55,9 +62,7 @@ pub enum BotState {
 #[derive(Component)]
 pub struct BotMotion {
     pub acceleration: f32,
-    pub drag: Vec3,
     pub angular_steer: f32,
-    pub velocity: Vec3,
     pub direction: Vec3,
     pub nearest_obstacle: (f32, Dir3),
     pub last_dir: Option<Dir3>,
@@ -68,9 +73,7 @@ impl Default for BotMotion {
     fn default() -> Self {
         Self {
             acceleration: 5.,
-            drag: Vec3::ZERO,
             angular_steer: 40.,
-            velocity: Vec3::ZERO,
             direction: Vec3::Z,
             nearest_obstacle: (f32::INFINITY, Dir3::Y),
             last_dir: None,
@@ -138,38 +141,34 @@ impl Plugin for BotPlugin {
         app.add_systems(OnEnter(InGameStates::Setup), setup.in_set(SetupSet::InGame))
             .add_systems(OnExit(GameState::Game), clear_resources)
             .add_systems(
-                Update,
-                (
-                    spawn_bots,
-                    thrust_control,
-                    chase_target,
-                    avoid_crash,
-                    collider::collision_response::<BotMarker>,
-                    despawn_dead_bots,
-                )
+                FixedUpdate,
+                (spawn_bots, thrust_control, chase_target, avoid_crash)
                     .chain()
                     .in_set(UpdateSet::InGame)
                     .run_if(in_state(GameState::Game)),
             )
             .add_systems(
-                Update,
+                FixedUpdate,
                 (shoot_target, shoot_turret::<BotTurret>)
                     .chain()
                     .in_set(UpdateSet::InGame)
                     .run_if(in_state(GameState::Game)),
+            )
+            .add_systems(
+                FixedUpdate,
+                count_destroyed_bots.in_set(DespawnSet::InGame(InGameSet::Bots)),
             );
     }
 }
 
-fn despawn_dead_bots(
-    query: Query<(Entity, &Health), With<BotMarker>>,
-    mut commands: Commands,
+fn count_destroyed_bots(
+    query: Query<(), With<BotMarker>>,
+    mut ev_destroyed: EventReader<EntityDestroyed>,
     mut bot_spawner: ResMut<BotSpawner>,
 ) {
-    for (ent, health) in query.iter() {
-        if health.0 <= 0. {
+    for ev in ev_destroyed.read() {
+        if query.contains(ev.entity) {
             bot_spawner.active_bots -= 1;
-            commands.entity(ent).despawn_recursive();
         }
     }
 }
@@ -195,8 +194,9 @@ fn spawn_bots(
         }
         bot_spawner.next_bot = (bot_spawner.next_bot + 1) % 3;
 
-        // todo: this code line is not safe, it's only for temporary use
-        let target = query.single().translation;
+        let Ok(target) = query.get_single().map(|t| t.translation) else {
+            return;
+        };
         let mut rng = rand::rng();
         let (x, y, z) = (
             rng.random_range(-1.0..1.0),
@@ -213,6 +213,7 @@ fn spawn_bots(
             .spawn((
                 SceneRoot(bot_scene),
                 BotMotion::default(),
+                RigidBody::new(BOT_MASS).with_drag(BOT_DRAG, 0.),
                 BotState::Chasing,
                 BotMarker,
                 Bot::default(),
@@ -221,14 +222,7 @@ fn spawn_bots(
                 Health(1000.0),
                 HomingMissileTarget,
                 ColliderMarker,
-                ColliderInfo {
-                    collider_type: ColliderType::Sphere,
-                    collider: Arc::new(RwLock::new(SphericalCollider {
-                        radius: 0.3,
-                        center: Vec3::ZERO,
-                    })),
-                    immune_to: None,
-                },
+                AutoCollider::capsule(CollisionLayers::enemy()),
                 ExplosibleObjectMarker,
                 (
                     AudioPlayer(audio_assets.throttle_up.clone()),
@@ -237,10 +231,23 @@ fn spawn_bots(
                 transform,
             ))
             .id();
-        commands.entity(bot).insert(CollisionDamage {
-            damage: 100.,
-            from: Some(bot),
-        });
+        commands.entity(bot).insert((
+            CollisionDamage {
+                damage: 100.,
+                kind: DamageType::Collision,
+                from: Some(bot),
+                mode: DamageMode::Interval(Duration::from_millis(500)),
+            },
+            Restitution(0.5),
+            // plated hulls shrug off lasers and ramming but not missiles
+            Armor {
+                kinetic: 0.2,
+                explosive: 0.,
+                energy: 0.5,
+                collision: 0.5,
+            },
+            Faction::Bots,
+        ));
         commands.entity(bot).with_children(|parent| {
             parent.spawn((
                 Transform::from_xyz(0., 0., 0.),
@@ -249,6 +256,7 @@ fn spawn_bots(
                     speed: 20.,
                     bullet_size: 0.0002,
                     shooter: Some(parent.parent_entity()),
+                    bullet_layers: CollisionLayers::enemy_projectile(),
                     ..default()
                 }),
                 GameObjectMarker,
@@ -269,29 +277,22 @@ fn spawn_bots(
 fn chase_target(
     target_query: Query<&Transform, With<BotTargetMarker>>,
     mut bot_query: Query<
-        (&mut Transform, &mut BotState, &mut BotMotion),
+        (&Transform, &BotState, &mut BotMotion, &mut RigidBody),
         (With<BotMarker>, Without<BotTargetMarker>),
     >,
-    time: Res<Time>,
 ) {
-    let t_trans = target_query.single();
+    let Ok(t_trans) = target_query.get_single() else {
+        return;
+    };
 
-    for (mut trans, state, mut motion) in bot_query.iter_mut() {
+    for (trans, state, mut motion, mut body) in bot_query.iter_mut() {
         // let target_distance = (t_trans.translation.clone() - trans.translation.clone()).length();
         motion.target_vicinity =
             BotMotion::estimate_vicintiy((t_trans.translation - trans.translation).length());
-        let t = time.delta_secs();
         match *state {
             BotState::Chasing => {
-                let drag = motion.drag.clone();
-                let velocity =
-                    motion.direction.clone().normalize_or_zero() * motion.acceleration.clone() * t
-                        + motion.velocity.clone()
-                        + drag * t;
-                motion.velocity = velocity;
-                trans.translation += motion.velocity * t;
-                motion.drag = -motion.velocity * 2.;
-                // info!("Velocityy bot {}", motion.velocity.length());
+                body.add_acceleration(motion.direction.normalize_or_zero() * motion.acceleration);
+                // info!("Velocityy bot {}", body.velocity.length());
             }
             _ => (),
         }
@@ -325,17 +326,17 @@ fn thrust_control(mut query_bots: Query<&mut BotMotion, With<BotMarker>>, time:
     }
 }
 
-fn avoid_crash(mut query: Query<(&mut BotMotion, &Transform), With<BotMarker>>, time: Res<Time>) {
+fn avoid_crash(mut query: Query<(&mut RigidBody, &Transform), With<BotMarker>>) {
     let mut bot_iter = query.iter_combinations_mut();
 
-    while let Some([(mut bm1, t1), (_, t2)]) = bot_iter.fetch_next() {
+    while let Some([(mut b1, t1), (_, t2)]) = bot_iter.fetch_next() {
         let diff_vec = t2.translation - t1.translation;
         if diff_vec.length_squared() < 0.5 {
             let drag_vec = diff_vec.normalize();
-            let drag_mag = bm1.velocity.dot(drag_vec);
-            bm1.velocity -= drag_mag * drag_vec * time.delta_secs();
+            let drag_mag = b1.velocity.dot(drag_vec);
+            b1.add_acceleration(-drag_mag * drag_vec);
             if drag_mag == 0. {
-                bm1.velocity -= drag_vec * time.delta_secs();
+                b1.add_acceleration(-drag_vec);
             }
         }
     }
@@ -349,7 +350,9 @@ fn aim_target(
     >,
     time: Res<Time>,
 ) {
-    let t_trans = target_query.single();
+    let Ok(t_trans) = target_query.get_single() else {
+        return;
+    };
     for (mut trans, mut motion, state) in bot_query.iter_mut() {
         match *state {
             BotState::Chasing => {
@@ -371,21 +374,45 @@ fn aim_target(
     }
 }
 
+// nothing solid between the bot and the target, projectiles and the bot itself are ignored
+fn in_line_of_sight(
+    spatial_query: &SpatialQuery,
+    bot: Entity,
+    origin: Vec3,
+    target: Entity,
+    to_target: Vec3,
+) -> bool {
+    let solid = CollisionLayer::Player as u32
+        | CollisionLayer::Enemy as u32
+        | CollisionLayer::Environment as u32;
+    spatial_query
+        .cast_ray(origin, to_target, to_target.length(), |e, ci| {
+            e != bot && (ci.layers.memberships & solid) != 0
+        })
+        .is_none_or(|hit| hit.entity == target)
+}
+
 fn shoot_target(
-    target_query: Query<&Transform, With<BotTargetMarker>>,
+    target_query: Query<(Entity, &Transform), With<BotTargetMarker>>,
     bot_query: Query<
-        (&Transform, &Children, &BotMotion),
+        (Entity, &Transform, &Children, &BotMotion, Option<&Faction>),
         (With<BotMarker>, Without<BotTargetMarker>),
     >,
     mut bot_turret: Query<(Entity, &mut Turret), (With<TurretMarker>, With<BotTurret>)>,
+    faction_query: Query<&Faction>,
+    faction_table: Res<FactionTable>,
+    spatial_query: SpatialQuery,
     mut ev_turret_off: EventWriter<ShootTurretEventOff>,
     mut ev_turret_on: EventWriter<ShootTurretEventOn>,
 ) {
-    'outer: for (b_trans, children, b_motion) in bot_query.iter() {
+    'outer: for (bot, b_trans, children, b_motion, faction) in bot_query.iter() {
         let mut dist: Vec3;
         let mut angle: f32;
 
-        for trans in target_query.iter() {
+        for (target, trans) in target_query.iter() {
+            if !faction_table.is_hostile(faction, faction_query.get(target).ok()) {
+                continue;
+            }
             dist = trans.translation - b_trans.translation;
             angle = b_motion
                 .direction
@@ -395,6 +422,7 @@ fn shoot_target(
             if dist.length() <= SHOOT_VICINITY_DISTANCE
                 && angle.acos() <= SHOOT_VICINITY_ANGLE.to_radians()
                 && angle >= 0.
+                && in_line_of_sight(&spatial_query, bot, b_trans.translation, target, dist)
             {
                 for child in children {
                     if let Ok((ent, mut b_turret)) = bot_turret.get_mut(child.clone()) {
@@ -1,4 +1,4 @@
-use super::movement::{Direction, Inertia};
+use super::movement::{Direction, RigidBody};
 use super::spaceship::{Entities, SpaceShip};
 use super::GameObjectMarker;
 use crate::controls::Controls;
@@ -227,7 +227,7 @@ fn sync_with_cam<T: Component>(
 fn follow_spaceship(
     mut cam_query: Query<(&mut Transform, &CameraMode), With<MyCameraMarker>>,
     mut sp_query: Query<
-        (&Transform, &mut Direction, &Inertia),
+        (&Transform, &mut Direction, &RigidBody),
         (With<SpaceShip>, Without<MyCameraMarker>),
     >,
     mut rc_query: Query<
@@ -241,14 +241,16 @@ fn follow_spaceship(
     entity: Res<Entities>,
     time: Res<Time>,
 ) {
-    let (trans, sp_dir, iner) = sp_query
-        .get_mut(entity.player.unwrap())
-        .expect("Error while player!");
+    let Some(Ok((trans, sp_dir, body))) = entity.player.map(|player| sp_query.get_mut(player))
+    else {
+        return;
+    };
     let v = trans.translation.clone();
 
-    let (mut camera, camera_mode) = cam_query
-        .get_mut(entity.camera.unwrap())
-        .expect("Can't get entitiy camera");
+    let Some(Ok((mut camera, camera_mode))) = entity.camera.map(|cam| cam_query.get_mut(cam))
+    else {
+        return;
+    };
 
     let (d, s) = match camera_mode.view_mode {
         ViewMode::FirstPerson((x, y)) => (x, y),
@@ -256,15 +258,15 @@ fn follow_spaceship(
         ViewMode::ThirdPerson2((x, y)) => (x, y),
     };
     //* find a way to calcuate a factor so that the camera speed changes with spacecraft velocity
-    // let factor = iner.velocity.0.clone()
-    //     - Vec3::new(1., 1., 1.) * iner.velocity.0.clone().normalize_or_zero();
+    // let factor = body.velocity
+    //     - Vec3::new(1., 1., 1.) * body.velocity.normalize_or_zero();
 
     let cam = camera.translation.clone();
     camera.translation += (v - sp_dir.0.normalize().clone() * d * 2. - cam)
         * if s == 0. {
             1.
         } else {
-            time.delta_secs() * (iner.velocity.0.length() * 2. + 2.)
+            time.delta_secs() * (body.velocity.length() * 2. + 2.)
         };
 
     camera.translation += if s == 0. {
@@ -302,7 +304,9 @@ fn camera_view(
     time: Res<Time>,
 ) {
     for (mut trans, mut camera_mode) in query.iter_mut() {
-        let (sp_trans, mut sp_visibility) = sp_query.single_mut();
+        let Ok((sp_trans, mut sp_visibility)) = sp_query.get_single_mut() else {
+            return;
+        };
 
         if keys.just_pressed(controls.camera_view.unwrap()) {
             camera_mode.view_mode = match camera_mode.view_mode {
@@ -1,51 +1,229 @@
-use crate::asset_loader::AudioAssets;
-use crate::game::bots::BotMarker;
 use crate::sets::*;
 use bevy::prelude::*;
 use bevy::utils::info;
 
-use super::explosion::ExplosibleObjectMarker;
-use std::sync::{Arc, RwLock};
+use super::damage::DamageType;
+use super::movement::RigidBody;
+use super::oct_tree::OctTree;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
-pub trait Collider: Send + Sync {
-    fn check_collision_with_sphere(&self, op: &Arc<RwLock<dyn Collider>>) -> bool;
-    fn check_collision_with_point(&self, op: &Arc<RwLock<dyn Collider>>) -> bool;
-    fn get_radius(&self) -> Result<f32, ()>;
-    fn get_center(&self) -> Result<Vec3, ()>;
-    fn set_center(&mut self, c: Vec3) -> Result<(), ()>;
+// contact between two colliders, the normal points from the other collider towards this one
+#[derive(Clone, Copy, Debug)]
+pub struct Contact {
+    pub normal: Vec3,
+    pub penetration: f32,
+    pub point: Vec3,
 }
 
-#[derive(Clone)]
-pub enum ColliderType {
-    Sphere,
-    Box,
-    Point,
+impl Contact {
+    pub fn flipped(self) -> Self {
+        Self {
+            normal: -self.normal,
+            ..self
+        }
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub struct SphericalCollider {
     pub radius: f32,
     pub center: Vec3,
 }
 
-struct BoxCollider;
+// oriented bounding box, half_extents are along the local axes of the entity
+#[derive(Clone, Copy)]
+pub struct BoxCollider {
+    pub half_extents: Vec3,
+    pub center: Vec3,
+    pub rotation: Quat,
+}
+
+#[derive(Clone, Copy)]
 pub struct PointCollider {
     pub center: Vec3,
 }
 
+// sphere swept along the local z axis, half_length does not include the rounded caps
+#[derive(Clone, Copy)]
+pub struct CapsuleCollider {
+    pub radius: f32,
+    pub half_length: f32,
+    pub center: Vec3,
+    pub rotation: Quat,
+}
+
+impl CapsuleCollider {
+    pub fn endpoints(&self) -> (Vec3, Vec3) {
+        let offset = self.rotation * Vec3::Z * self.half_length;
+        (self.center - offset, self.center + offset)
+    }
+}
+
+// stored inline in the component so the narrow phase reads plain data,
+// center and rotation are synced from the GlobalTransform once per frame
+#[derive(Clone, Copy)]
+pub enum ColliderShape {
+    Sphere(SphericalCollider),
+    Box(BoxCollider),
+    Point(PointCollider),
+    Capsule(CapsuleCollider),
+}
+
+impl ColliderShape {
+    pub fn center(&self) -> Vec3 {
+        match self {
+            ColliderShape::Sphere(s) => s.center,
+            ColliderShape::Box(b) => b.center,
+            ColliderShape::Point(p) => p.center,
+            ColliderShape::Capsule(c) => c.center,
+        }
+    }
+
+    // radius of the bounding sphere
+    pub fn radius(&self) -> f32 {
+        match self {
+            ColliderShape::Sphere(s) => s.radius,
+            ColliderShape::Box(b) => b.half_extents.length(),
+            ColliderShape::Point(_) => 0.,
+            ColliderShape::Capsule(c) => c.half_length + c.radius,
+        }
+    }
+
+    // the shape as a segment swept by a sphere, spheres and points have a zero length segment,
+    // boxes are only approximated by their bounding sphere here
+    fn core(&self) -> (Vec3, Vec3, f32) {
+        match self {
+            ColliderShape::Capsule(c) => {
+                let (a, b) = c.endpoints();
+                (a, b, c.radius)
+            }
+            _ => (self.center(), self.center(), self.radius()),
+        }
+    }
+
+    fn set_transform(&mut self, center: Vec3, rotation: Quat) {
+        match self {
+            ColliderShape::Sphere(s) => s.center = center,
+            ColliderShape::Box(b) => {
+                b.center = center;
+                b.rotation = rotation;
+            }
+            ColliderShape::Point(p) => p.center = center,
+            ColliderShape::Capsule(c) => {
+                c.center = center;
+                c.rotation = rotation;
+            }
+        }
+    }
+
+    // the normal points from other towards self, points are spheres of radius 0
+    pub fn contact(&self, other: &ColliderShape) -> Option<Contact> {
+        match (self, other) {
+            (ColliderShape::Box(b1), ColliderShape::Box(b2)) => b1.box_contact(b2),
+            (ColliderShape::Box(b), _) => {
+                let (from, to, radius) = other.core();
+                b.sphere_contact(b.closest_on_segment(from, to), radius)
+                    .map(Contact::flipped)
+            }
+            (_, ColliderShape::Box(b)) => {
+                let (from, to, radius) = self.core();
+                b.sphere_contact(b.closest_on_segment(from, to), radius)
+            }
+            _ => {
+                let (a1, b1, r1) = self.core();
+                let (a2, b2, r2) = other.core();
+                let (p1, p2) = closest_between_segments(a1, b1, a2, b2);
+                sphere_contact(p1, r1, p2, r2)
+            }
+        }
+    }
+
+    pub fn intersects(&self, other: &ColliderShape) -> bool {
+        self.contact(other).is_some()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DamageMode {
+    // every frame the colliders overlap
+    Continuous,
+    // once when the collision starts
+    OnEnter,
+    // when the collision starts and then once per interval while it lasts
+    Interval(Duration),
+}
+
 #[derive(Component, Clone, Copy)]
 pub struct CollisionDamage {
     pub damage: f32,
+    pub kind: DamageType,
     // this field is only required when checking collision between bullets and objects
     pub from: Option<Entity>,
+    pub mode: DamageMode,
 }
 
-#[derive(Component)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CollisionLayer {
+    Player = 1 << 0,
+    Enemy = 1 << 1,
+    PlayerProjectile = 1 << 2,
+    EnemyProjectile = 1 << 3,
+    Environment = 1 << 4,
+}
+
+// a pair is only checked when each one is a member of a layer the other one filters for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CollisionLayers {
+    pub memberships: u32,
+    pub filters: u32,
+}
+
+impl CollisionLayers {
+    pub fn new(memberships: &[CollisionLayer], filters: &[CollisionLayer]) -> Self {
+        Self {
+            memberships: memberships.iter().fold(0, |bits, l| bits | *l as u32),
+            filters: filters.iter().fold(0, |bits, l| bits | *l as u32),
+        }
+    }
+
+    pub fn interacts_with(&self, other: &CollisionLayers) -> bool {
+        (self.memberships & other.filters) != 0 && (other.memberships & self.filters) != 0
+    }
+
+    pub fn player() -> Self {
+        use CollisionLayer::*;
+        Self::new(&[Player], &[Enemy, EnemyProjectile, Environment])
+    }
+
+    pub fn enemy() -> Self {
+        use CollisionLayer::*;
+        Self::new(&[Enemy], &[Player, Enemy, PlayerProjectile, Environment])
+    }
+
+    pub fn player_projectile() -> Self {
+        use CollisionLayer::*;
+        Self::new(&[PlayerProjectile], &[Enemy, EnemyProjectile, Environment])
+    }
+
+    pub fn enemy_projectile() -> Self {
+        use CollisionLayer::*;
+        Self::new(&[EnemyProjectile], &[Player, PlayerProjectile, Environment])
+    }
+
+    pub fn environment() -> Self {
+        use CollisionLayer::*;
+        Self::new(
+            &[Environment],
+            &[Player, Enemy, PlayerProjectile, EnemyProjectile],
+        )
+    }
+}
+
+#[derive(Component, Clone, Copy)]
 pub struct ColliderInfo {
-    pub collider_type: ColliderType,
-    pub collider: Arc<RwLock<dyn Collider>>,
-    pub immune_to: Option<Vec<Entity>>,
-    // pub collider: T,
+    pub shape: ColliderShape,
+    pub layers: CollisionLayers,
 }
 
 #[derive(Event)]
@@ -53,93 +231,302 @@ pub enum CollisionEvents {
     TakeDamage(Entity, CollisionDamage, Entity),
 }
 
-impl Collider for SphericalCollider {
-    fn check_collision_with_point(&self, op: &Arc<RwLock<dyn Collider>>) -> bool {
-        if let Ok(center) = op.as_ref().read().ok().unwrap().get_center() {
-            (self.center - center).length() <= self.radius
+#[derive(Event, Clone, Copy)]
+pub struct CollisionStarted(pub Entity, pub Entity);
+
+#[derive(Event, Clone, Copy)]
+pub struct CollisionOngoing(pub Entity, pub Entity);
+
+#[derive(Event, Clone, Copy)]
+pub struct CollisionEnded(pub Entity, pub Entity);
+
+// sent for overlapping pairs, the contact normal points from the second entity to the first
+#[derive(Event, Clone, Copy)]
+pub struct CollisionContact(pub Entity, pub Entity, pub Contact);
+
+// bodies with restitution get pushed out of whatever they hit and bounce off it,
+// the rest are treated as static
+#[derive(Component, Clone, Copy)]
+pub struct Restitution(pub f32);
+
+// pairs colliding on the last frame, stored with the smaller entity first
+#[derive(Resource, Default)]
+pub struct CollisionTracker {
+    pairs: HashSet<(Entity, Entity)>,
+    // when damage was last applied from the first entity to the second
+    last_damage: HashMap<(Entity, Entity), Duration>,
+}
+
+impl CollisionTracker {
+    fn pair(e1: Entity, e2: Entity) -> (Entity, Entity) {
+        if e1 < e2 {
+            (e1, e2)
         } else {
-            panic!("center should be present");
+            (e2, e1)
         }
     }
-    fn check_collision_with_sphere(&self, op: &Arc<RwLock<dyn Collider>>) -> bool {
-        if let (Ok(center), Ok(radius)) = (
-            op.as_ref().read().ok().unwrap().get_center(),
-            op.as_ref().read().ok().unwrap().get_radius(),
-        ) {
-            (self.center - center).length() <= self.radius + radius
-        } else {
-            panic!("center or radius should be present");
+
+    fn should_damage(
+        &mut self,
+        from: Entity,
+        to: Entity,
+        damage: &CollisionDamage,
+        started: bool,
+        now: Duration,
+    ) -> bool {
+        match damage.mode {
+            DamageMode::Continuous => true,
+            DamageMode::OnEnter => started,
+            DamageMode::Interval(interval) => {
+                let due = match self.last_damage.get(&(from, to)) {
+                    Some(last) => now.saturating_sub(*last) >= interval,
+                    None => true,
+                };
+                if started || due {
+                    self.last_damage.insert((from, to), now);
+                    true
+                } else {
+                    false
+                }
+            }
         }
     }
-    fn get_radius(&self) -> Result<f32, ()> {
-        Ok(self.radius)
-    }
-    fn get_center(&self) -> Result<Vec3, ()> {
-        Ok(self.center)
-    }
-    fn set_center(&mut self, c: Vec3) -> Result<(), ()> {
-        self.center = c;
-        Ok(())
-    }
 }
 
-impl Collider for BoxCollider {
-    fn check_collision_with_point(&self, op: &Arc<RwLock<dyn Collider>>) -> bool {
-        todo!();
+// normal points from the second sphere towards the first
+fn sphere_contact(c1: Vec3, r1: f32, c2: Vec3, r2: f32) -> Option<Contact> {
+    let diff = c1 - c2;
+    let dist = diff.length();
+    if dist > r1 + r2 {
+        return None;
     }
-    fn check_collision_with_sphere(&self, op: &Arc<RwLock<dyn Collider>>) -> bool {
-        todo!();
+    let normal = diff.normalize_or(Vec3::Y);
+    Some(Contact {
+        normal,
+        penetration: r1 + r2 - dist,
+        point: c2 + normal * r2,
+    })
+}
+
+impl BoxCollider {
+    fn axes(&self) -> [Vec3; 3] {
+        [
+            self.rotation * Vec3::X,
+            self.rotation * Vec3::Y,
+            self.rotation * Vec3::Z,
+        ]
     }
-    fn get_center(&self) -> Result<Vec3, ()> {
-        todo!();
+
+    // point in world space converted to the local frame of the box
+    fn to_local(&self, p: Vec3) -> Vec3 {
+        self.rotation.inverse() * (p - self.center)
     }
-    fn get_radius(&self) -> Result<f32, ()> {
-        todo!();
+
+    pub fn contains_point(&self, p: Vec3) -> bool {
+        let local = self.to_local(p).abs();
+        local.x <= self.half_extents.x
+            && local.y <= self.half_extents.y
+            && local.z <= self.half_extents.z
     }
-    fn set_center(&mut self, c: Vec3) -> Result<(), ()> {
-        // self.center = c;
-        Ok(())
+
+    pub fn closest_point(&self, p: Vec3) -> Vec3 {
+        let local = self
+            .to_local(p)
+            .clamp(-self.half_extents, self.half_extents);
+        self.center + self.rotation * local
     }
-}
 
-impl Collider for PointCollider {
-    fn check_collision_with_point(&self, op: &Arc<RwLock<dyn Collider>>) -> bool {
-        if let Ok(center) = op.as_ref().read().ok().unwrap().get_center() {
-            self.center == center
+    // normal points from the box towards the sphere
+    pub fn sphere_contact(&self, center: Vec3, radius: f32) -> Option<Contact> {
+        let closest = self.closest_point(center);
+        let diff = center - closest;
+        let dist = diff.length();
+        if dist > radius {
+            return None;
+        }
+        if dist > 0. {
+            return Some(Contact {
+                normal: diff / dist,
+                penetration: radius - dist,
+                point: closest,
+            });
+        }
+        // center is inside the box, push it out through the nearest face
+        let local = self.to_local(center);
+        let depth = self.half_extents - local.abs();
+        let axis = if depth.x <= depth.y && depth.x <= depth.z {
+            0
+        } else if depth.y <= depth.z {
+            1
         } else {
-            panic!("Center should be present");
+            2
+        };
+        let sign = if local[axis] >= 0. { 1. } else { -1. };
+        let mut face = local;
+        face[axis] = sign * self.half_extents[axis];
+        Some(Contact {
+            normal: self.axes()[axis] * sign,
+            penetration: radius + depth[axis],
+            point: self.center + self.rotation * face,
+        })
+    }
+
+    // slab test of the segment against the box grown by radius
+    pub fn segment_hits(&self, from: Vec3, to: Vec3, radius: f32) -> bool {
+        let start = self.to_local(from);
+        let dir = self.to_local(to) - start;
+        let half = self.half_extents + Vec3::splat(radius);
+        let (mut t_min, mut t_max) = (0_f32, 1_f32);
+        for i in 0..3 {
+            if dir[i].abs() < f32::EPSILON {
+                if start[i].abs() > half[i] {
+                    return false;
+                }
+            } else {
+                let inv = 1. / dir[i];
+                let t1 = (-half[i] - start[i]) * inv;
+                let t2 = (half[i] - start[i]) * inv;
+                t_min = t_min.max(t1.min(t2));
+                t_max = t_max.min(t1.max(t2));
+                if t_min > t_max {
+                    return false;
+                }
+            }
         }
+        true
     }
-    fn check_collision_with_sphere(&self, op: &Arc<RwLock<dyn Collider>>) -> bool {
-        if let (Ok(center), Ok(radius)) = (
-            op.as_ref().read().ok().unwrap().get_center(),
-            op.as_ref().read().ok().unwrap().get_radius(),
-        ) {
-            (self.center - center).length() <= radius
-        } else {
-            panic!("Center or radius should be present");
+
+    // separating axis test, 3 face axes of each box and 9 edge cross products,
+    // the axis with the least overlap gives the normal which points from other to self
+    pub fn box_contact(&self, other: &BoxCollider) -> Option<Contact> {
+        let a = self.axes();
+        let b = other.axes();
+        let t = other.center - self.center;
+
+        let mut axes = Vec::<Vec3>::with_capacity(15);
+        axes.extend_from_slice(&a);
+        axes.extend_from_slice(&b);
+        for i in 0..3 {
+            for j in 0..3 {
+                // parallel edges give a zero axis which can't separate anything
+                if let Some(axis) = a[i].cross(b[j]).try_normalize() {
+                    axes.push(axis);
+                }
+            }
         }
+
+        let mut normal = Vec3::Y;
+        let mut penetration = f32::INFINITY;
+        for axis in axes {
+            let ra = self.half_extents.x * a[0].dot(axis).abs()
+                + self.half_extents.y * a[1].dot(axis).abs()
+                + self.half_extents.z * a[2].dot(axis).abs();
+            let rb = other.half_extents.x * b[0].dot(axis).abs()
+                + other.half_extents.y * b[1].dot(axis).abs()
+                + other.half_extents.z * b[2].dot(axis).abs();
+            let overlap = ra + rb - t.dot(axis).abs();
+            if overlap < 0. {
+                return None;
+            }
+            if overlap < penetration {
+                penetration = overlap;
+                normal = if t.dot(axis) > 0. { -axis } else { axis };
+            }
+        }
+        Some(Contact {
+            normal,
+            penetration,
+            point: (self.closest_point(other.center) + other.closest_point(self.center)) / 2.,
+        })
     }
-    fn get_center(&self) -> Result<Vec3, ()> {
-        Ok(self.center)
+
+    // point of the segment close to the box, exact for points and a good guess for short segments
+    pub fn closest_on_segment(&self, from: Vec3, to: Vec3) -> Vec3 {
+        let p = closest_on_segment(from, to, self.center);
+        closest_on_segment(from, to, self.closest_point(p))
     }
-    fn get_radius(&self) -> Result<f32, ()> {
-        Err(())
+
+    // distance along the normalized dir to where the ray enters the box grown by radius,
+    // with the normal of the face it enters through
+    pub fn ray_hit(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+        radius: f32,
+    ) -> Option<(f32, Vec3)> {
+        let start = self.to_local(origin);
+        let local_dir = self.rotation.inverse() * dir;
+        let half = self.half_extents + Vec3::splat(radius);
+        let (mut t_min, mut t_max) = (0_f32, max_dist);
+        let mut normal = -dir;
+        for i in 0..3 {
+            if local_dir[i].abs() < f32::EPSILON {
+                if start[i].abs() > half[i] {
+                    return None;
+                }
+                continue;
+            }
+            let inv = 1. / local_dir[i];
+            let t1 = (-half[i] - start[i]) * inv;
+            let t2 = (half[i] - start[i]) * inv;
+            if t1.min(t2) > t_min {
+                t_min = t1.min(t2);
+                normal = self.axes()[i] * -local_dir[i].signum();
+            }
+            t_max = t_max.min(t1.max(t2));
+            if t_min > t_max {
+                return None;
+            }
+        }
+        Some((t_min, normal))
     }
 
-    fn set_center(&mut self, c: Vec3) -> Result<(), ()> {
-        self.center = c;
-        Ok(())
+    pub fn intersects_box(&self, other: &BoxCollider) -> bool {
+        self.box_contact(other).is_some()
     }
 }
+
 #[derive(Component, Clone)]
 pub struct ColliderMarker;
 
+// for projectiles fast enough to skip over a collider between two frames,
+// the path from the previous center to the current one is checked instead
+#[derive(Component, Clone, Copy)]
+pub struct SweptCollider {
+    pub previous_center: Vec3,
+}
+
 pub struct ColliderPlugin;
 impl Plugin for ColliderPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<CollisionEvents>()
-            .add_systems(Update, detect_collisions.in_set(UpdateSet::InGame));
+            .add_event::<CollisionStarted>()
+            .add_event::<CollisionOngoing>()
+            .add_event::<CollisionEnded>()
+            .add_event::<CollisionContact>()
+            .init_resource::<CollisionTracker>()
+            .add_systems(
+                FixedUpdate,
+                (
+                    sync_collider_transforms,
+                    detect_collisions,
+                    resolve_contacts,
+                    track_swept_colliders,
+                )
+                    .chain()
+                    .in_set(UpdateSet::InGame),
+            );
+    }
+}
+
+// colliders follow their entity, synced once here so the narrow phase only reads
+fn sync_collider_transforms(
+    mut query: Query<(&GlobalTransform, &mut ColliderInfo), Changed<GlobalTransform>>,
+) {
+    for (gt, mut ci) in query.iter_mut() {
+        let (_, rotation, center) = gt.to_scale_rotation_translation();
+        ci.shape.set_transform(center, rotation);
     }
 }
 
@@ -147,141 +534,217 @@ fn detect_collisions(
     query: Query<
         (
             Entity,
-            &GlobalTransform,
             &ColliderInfo,
             Option<&CollisionDamage>,
+            Option<&SweptCollider>,
         ),
         With<ColliderMarker>,
     >,
+    oct_tree: Res<OctTree>,
+    mut tracker: ResMut<CollisionTracker>,
     mut ev_writer: EventWriter<CollisionEvents>,
+    mut ev_started: EventWriter<CollisionStarted>,
+    mut ev_ongoing: EventWriter<CollisionOngoing>,
+    mut ev_ended: EventWriter<CollisionEnded>,
+    mut ev_contact: EventWriter<CollisionContact>,
+    time: Res<Time>,
 ) {
-    for [(e1, g1, c1, cd1), (e2, g2, c2, cd2)] in query.iter_combinations() {
-        _ = c1
-            .collider
-            .as_ref()
-            .write()
-            .ok()
-            .unwrap()
-            .set_center(g1.translation());
-        _ = c2
-            .collider
-            .as_ref()
-            .write()
-            .ok()
-            .unwrap()
-            .set_center(g2.translation());
-        match &c2.collider_type {
-            ColliderType::Sphere => {
-                if c1
-                    .collider
-                    .as_ref()
-                    .read()
-                    .ok()
-                    .unwrap()
-                    .check_collision_with_sphere(&c2.collider)
-                {
-                    if let Some(cd) = cd1 {
-                        ev_writer.send(CollisionEvents::TakeDamage(e2, cd.clone(), e1));
-                    }
-
-                    if let Some(cd) = cd2 {
-                        ev_writer.send(CollisionEvents::TakeDamage(e1, cd.clone(), e2));
-                    }
+    let now = time.elapsed();
+    let mut current = HashSet::<(Entity, Entity)>::new();
+    for (n1, n2) in oct_tree.get_candidate_pairs() {
+        let Ok([(e1, c1, cd1, sw1), (e2, c2, cd2, sw2)]) = query.get_many([n1.entity, n2.entity])
+        else {
+            continue;
+        };
+        if !c1.layers.interacts_with(&c2.layers) {
+            continue;
+        }
+
+        let mut contact = None;
+        let collided = match (sw1, sw2) {
+            (None, None) => {
+                contact = c1.shape.contact(&c2.shape);
+                contact.is_some()
+            }
+            (Some(sw), None) => {
+                sweep_hits(&c1.shape, sw.previous_center, c1.shape.center(), &c2.shape)
+            }
+            (None, Some(sw)) => {
+                sweep_hits(&c2.shape, sw.previous_center, c2.shape.center(), &c1.shape)
+            }
+            (Some(sw1), Some(sw2)) => {
+                // sweep the first one relative to the second
+                let from = sw1.previous_center + (c2.shape.center() - sw2.previous_center);
+                sweep_hits(&c1.shape, from, c1.shape.center(), &c2.shape)
+            }
+        };
+
+        if collided {
+            let pair = CollisionTracker::pair(e1, e2);
+            if !current.insert(pair) {
+                continue;
+            }
+            if let Some(contact) = contact {
+                ev_contact.send(CollisionContact(e1, e2, contact));
+            }
+            let started = !tracker.pairs.contains(&pair);
+            if started {
+                ev_started.send(CollisionStarted(pair.0, pair.1));
+            } else {
+                ev_ongoing.send(CollisionOngoing(pair.0, pair.1));
+            }
+
+            if let Some(cd) = cd1 {
+                if tracker.should_damage(e1, e2, cd, started, now) {
+                    ev_writer.send(CollisionEvents::TakeDamage(e2, cd.clone(), e1));
                 }
             }
-            ColliderType::Point => {
-                // since bullets have range after which they despawn this should be scheduled properly
-                if c1
-                    .collider
-                    .as_ref()
-                    .read()
-                    .ok()
-                    .unwrap()
-                    .check_collision_with_point(&c2.collider)
-                {
-                    if let Some(cd) = cd1 {
-                        ev_writer.send(CollisionEvents::TakeDamage(e2, cd.clone(), e1));
-                    }
-
-                    if let Some(cd) = cd2 {
-                        ev_writer.send(CollisionEvents::TakeDamage(e1, cd.clone(), e2));
-                    }
+
+            if let Some(cd) = cd2 {
+                if tracker.should_damage(e2, e1, cd, started, now) {
+                    ev_writer.send(CollisionEvents::TakeDamage(e1, cd.clone(), e2));
                 }
             }
-            _ => (),
         }
     }
+
+    for pair in tracker.pairs.iter() {
+        if !current.contains(pair) {
+            ev_ended.send(CollisionEnded(pair.0, pair.1));
+        }
+    }
+    tracker
+        .last_damage
+        .retain(|(from, to), _| current.contains(&CollisionTracker::pair(*from, *to)));
+    tracker.pairs = current;
 }
 
-use super::explosion::{Explosion, ExplosionEvent};
-use super::spaceship::Health;
-pub fn collision_response<T: Component>(
-    mut query: Query<
-        (
-            Entity,
-            &Transform,
-            &ColliderInfo,
-            &mut Health,
-            Option<&ExplosibleObjectMarker>,
-        ),
-        With<T>,
-    >,
-    audio_asset: Res<AudioAssets>,
-    mut ev_reader: EventReader<CollisionEvents>,
-    mut ev_explode: EventWriter<ExplosionEvent>,
-) {
-    // let health = query.single();
-    for msg in ev_reader.read() {
-        match msg {
-            CollisionEvents::TakeDamage(e, d, e_with) => {
-                // info!("collision event received");
-                if let Ok((ent, trans, collider, mut health, explosible)) = query.get_mut(e.clone())
-                {
-                    // info!("heatlth {}", health.0);
-                    if d.from.is_some_and(|e| {
-                        e != ent
-                            && || -> bool {
-                                if collider.immune_to.is_some() {
-                                    for x in collider.immune_to.clone().unwrap() {
-                                        if x == e {
-                                            return false;
-                                        }
-                                    }
-                                }
-                                return true;
-                            }()
-                    }) || d.from.is_none()
-                    {
-                        if health.0 <= 0. {
-                            continue;
-                        }
-                        // info!("{:?} {:?}", d.from.unwrap(), ent);
-                        health.0 -= d.damage;
-                        if health.0 <= 0. && explosible.is_some() {
-                            // todo: condition if it is explosible
-                            ev_explode.send(ExplosionEvent {
-                                transform: trans.clone(),
-                                explosion: Explosion {
-                                    half_extent: collider
-                                        .collider
-                                        .read()
-                                        .unwrap()
-                                        .get_radius()
-                                        .unwrap()
-                                        / 2.0,
-                                    ..default()
-                                },
-                                sound: Some(audio_asset.explosion.clone()),
-                            });
-                        }
-                        info!("Health {}", health.0);
-                    }
+// checks the path travelled by the mover this frame against the other collider
+fn sweep_hits(mover: &ColliderShape, from: Vec3, to: Vec3, other: &ColliderShape) -> bool {
+    match other {
+        ColliderShape::Box(b) => b.segment_hits(from, to, mover.radius()),
+        _ => {
+            let (a, b, radius) = other.core();
+            let (p1, p2) = closest_between_segments(from, to, a, b);
+            (p1 - p2).length() <= radius + mover.radius()
+        }
+    }
+}
+
+pub fn segment_hits_sphere(from: Vec3, to: Vec3, center: Vec3, radius: f32) -> bool {
+    (closest_on_segment(from, to, center) - center).length() <= radius
+}
+
+pub fn closest_on_segment(from: Vec3, to: Vec3, p: Vec3) -> Vec3 {
+    let seg = to - from;
+    let len_sq = seg.length_squared();
+    if len_sq <= f32::EPSILON {
+        return from;
+    }
+    from + seg * ((p - from).dot(seg) / len_sq).clamp(0., 1.)
+}
+
+// closest points between the segments p1-q1 and p2-q2, either may have zero length
+pub fn closest_between_segments(p1: Vec3, q1: Vec3, p2: Vec3, q2: Vec3) -> (Vec3, Vec3) {
+    let d1 = q1 - p1;
+    let d2 = q2 - p2;
+    let r = p1 - p2;
+    let a = d1.length_squared();
+    let e = d2.length_squared();
+    let f = d2.dot(r);
+    if a <= f32::EPSILON && e <= f32::EPSILON {
+        return (p1, p2);
+    }
+    if a <= f32::EPSILON {
+        return (p1, closest_on_segment(p2, q2, p1));
+    }
+    if e <= f32::EPSILON {
+        return (closest_on_segment(p1, q1, p2), p2);
+    }
+    let c = d1.dot(r);
+    let b = d1.dot(d2);
+    let denom = a * e - b * b;
+    // parallel segments have no unique pair, any s works
+    let mut s = if denom > f32::EPSILON {
+        ((b * f - c * e) / denom).clamp(0., 1.)
+    } else {
+        0.
+    };
+    let mut t = (b * s + f) / e;
+    if t < 0. {
+        t = 0.;
+        s = (-c / a).clamp(0., 1.);
+    } else if t > 1. {
+        t = 1.;
+        s = ((b - c) / a).clamp(0., 1.);
+    }
+    (p1 + d1 * s, p2 + d2 * t)
+}
+
+type BodyQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static mut Transform,
+        &'static Restitution,
+        &'static mut RigidBody,
+    ),
+>;
+
+fn resolve_contacts(mut ev_contact: EventReader<CollisionContact>, mut query: BodyQuery) {
+    for CollisionContact(e1, e2, contact) in ev_contact.read() {
+        match (query.contains(*e1), query.contains(*e2)) {
+            (true, true) => {
+                let Ok([(mut t1, r1, mut b1), (mut t2, r2, mut b2)]) =
+                    query.get_many_mut([*e1, *e2])
+                else {
+                    continue;
+                };
+                // the lighter body takes more of the push
+                let w1 = inverse_mass(&b1);
+                let w2 = inverse_mass(&b2);
+                if w1 + w2 == 0. {
+                    continue;
                 }
+                let e = r1.0.min(r2.0);
+                let closing = (b1.velocity - b2.velocity).dot(contact.normal).min(0.);
+                let j = -closing * (1. + e) / (w1 + w2);
+                t1.translation += contact.normal * contact.penetration * w1 / (w1 + w2);
+                t2.translation -= contact.normal * contact.penetration * w2 / (w1 + w2);
+                b1.velocity += contact.normal * j * w1;
+                b2.velocity -= contact.normal * j * w2;
             }
+            (true, false) => bounce_off_static(&mut query, *e1, contact),
+            (false, true) => bounce_off_static(&mut query, *e2, &contact.flipped()),
+            (false, false) => (),
         }
     }
 }
 
+fn bounce_off_static(query: &mut BodyQuery, entity: Entity, contact: &Contact) {
+    let Ok((mut trans, restitution, mut body)) = query.get_mut(entity) else {
+        return;
+    };
+    trans.translation += contact.normal * contact.penetration;
+    // only reflect when moving into the other collider
+    let closing = body.velocity.dot(contact.normal).min(0.);
+    body.velocity -= contact.normal * closing * (1. + restitution.0);
+}
+
+fn inverse_mass(body: &RigidBody) -> f32 {
+    if body.mass > 0. {
+        1. / body.mass
+    } else {
+        0.
+    }
+}
+
+fn track_swept_colliders(mut query: Query<(&GlobalTransform, &mut SweptCollider)>) {
+    for (gt, mut swept) in query.iter_mut() {
+        swept.previous_center = gt.translation();
+    }
+}
+
 // do a study on conecpts used here
 // thread safe trait
 // Arc<dyn trait + Send + Sync>
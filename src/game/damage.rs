@@ -0,0 +1,227 @@
+use super::collider::{ColliderInfo, CollisionEvents};
+use super::explosion::{ExplosibleObjectMarker, Explosion, ExplosionEvent};
+use super::faction::{Faction, FactionTable};
+use super::spaceship::Health;
+use crate::asset_loader::AudioAssets;
+use crate::sets::*;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use std::collections::HashSet;
+
+// explosions smaller than this use the small explosion sound
+const SMALL_EXPLOSION_EXTENT: f32 = 0.2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DamageType {
+    // bullets and other solid projectiles
+    Kinetic,
+    Explosive,
+    Energy,
+    // ramming into ships and planets
+    Collision,
+}
+
+// fraction of each damage type that is blocked, 0 takes everything and 1 is immune
+#[derive(Component, Clone, Copy, Default)]
+pub struct Armor {
+    pub kinetic: f32,
+    pub explosive: f32,
+    pub energy: f32,
+    pub collision: f32,
+}
+
+impl Armor {
+    pub fn resistance(&self, kind: DamageType) -> f32 {
+        let r = match kind {
+            DamageType::Kinetic => self.kinetic,
+            DamageType::Explosive => self.explosive,
+            DamageType::Energy => self.energy,
+            DamageType::Collision => self.collision,
+        };
+        r.clamp(0., 1.)
+    }
+}
+
+// absorbs damage before health and recharges once nothing has hit it for regen_delay seconds
+#[derive(Component, Clone, Copy)]
+pub struct Shield {
+    pub capacity: f32,
+    pub current: f32,
+    pub regen_rate: f32,
+    pub regen_delay: f32,
+    since_hit: f32,
+}
+
+impl Shield {
+    pub fn new(capacity: f32, regen_rate: f32, regen_delay: f32) -> Self {
+        Self {
+            capacity,
+            current: capacity,
+            regen_rate,
+            regen_delay,
+            since_hit: 0.,
+        }
+    }
+
+    // returns what is left over for health
+    pub fn absorb(&mut self, damage: f32) -> f32 {
+        self.since_hit = 0.;
+        let absorbed = damage.min(self.current);
+        self.current -= absorbed;
+        damage - absorbed
+    }
+}
+
+// damage that made it through armor and shields
+#[derive(Event, Clone, Copy)]
+pub struct DamageDealt {
+    pub target: Entity,
+    // the shooter for projectiles, otherwise whatever was collided with
+    pub source: Entity,
+    pub amount: f32,
+    pub kind: DamageType,
+}
+
+// sent once when an entity dies or expires, it is despawned after the DespawnSet reacted to it.
+// killer is none for projectiles that ran out of range or time
+#[derive(Event, Clone, Copy)]
+pub struct EntityDestroyed {
+    pub entity: Entity,
+    pub killer: Option<Entity>,
+    pub position: Vec3,
+}
+
+// entities already reported destroyed this tick
+#[derive(Resource, Default)]
+struct DestroyedThisTick(HashSet<Entity>);
+
+// sends EntityDestroyed at most once per entity and tick, a projectile can
+// expire and get killed in the same tick
+#[derive(SystemParam)]
+pub struct DestroyedWriter<'w> {
+    events: EventWriter<'w, EntityDestroyed>,
+    sent: ResMut<'w, DestroyedThisTick>,
+}
+
+impl DestroyedWriter<'_> {
+    pub fn send(&mut self, event: EntityDestroyed) {
+        if self.sent.0.insert(event.entity) {
+            self.events.send(event);
+        }
+    }
+}
+
+fn clear_destroyed(mut sent: ResMut<DestroyedThisTick>) {
+    sent.0.clear();
+}
+
+pub struct DamagePlugin;
+impl Plugin for DamagePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<DamageDealt>()
+            .add_event::<EntityDestroyed>()
+            .init_resource::<DestroyedThisTick>()
+            .add_systems(FixedFirst, clear_destroyed)
+            .add_systems(
+                FixedUpdate,
+                (apply_damage, regenerate_shields).in_set(UpdateSet::InGame),
+            );
+    }
+}
+
+// damage that gets through armor and shield and has to be taken off health
+pub fn resolve_damage(
+    damage: f32,
+    kind: DamageType,
+    armor: Option<&Armor>,
+    shield: Option<Mut<Shield>>,
+) -> f32 {
+    let damage = damage * (1. - armor.map_or(0., |a| a.resistance(kind)));
+    match shield {
+        Some(mut shield) => shield.absorb(damage),
+        None => damage,
+    }
+}
+
+// the only place health is taken off, everything else reacts to the events sent here
+fn apply_damage(
+    mut query: Query<(
+        &Transform,
+        &mut Health,
+        Option<&ColliderInfo>,
+        Option<&Armor>,
+        Option<&mut Shield>,
+        Option<&ExplosibleObjectMarker>,
+    )>,
+    faction_query: Query<&Faction>,
+    faction_table: Res<FactionTable>,
+    audio_asset: Res<AudioAssets>,
+    mut ev_reader: EventReader<CollisionEvents>,
+    mut ev_damage: EventWriter<DamageDealt>,
+    mut ev_destroyed: DestroyedWriter,
+    mut ev_explode: EventWriter<ExplosionEvent>,
+) {
+    for CollisionEvents::TakeDamage(target, d, with) in ev_reader.read() {
+        // own projectiles never hurt the shooter
+        if d.from == Some(*target) {
+            continue;
+        }
+        let source = d.from.unwrap_or(*with);
+        // projectiles carry the faction of their shooter, which may have despawned since
+        let faction = faction_query
+            .get(*with)
+            .or_else(|_| faction_query.get(source))
+            .ok();
+        if !faction_table.can_damage(faction, faction_query.get(*target).ok()) {
+            continue;
+        }
+        let Ok((trans, mut health, collider, armor, shield, explosible)) = query.get_mut(*target)
+        else {
+            continue;
+        };
+        if health.0 <= 0. {
+            continue;
+        }
+        let amount = resolve_damage(d.damage, d.kind, armor, shield);
+        health.0 -= amount;
+        ev_damage.send(DamageDealt {
+            target: *target,
+            source,
+            amount,
+            kind: d.kind,
+        });
+        if health.0 > 0. {
+            continue;
+        }
+        ev_destroyed.send(EntityDestroyed {
+            entity: *target,
+            killer: Some(source),
+            position: trans.translation,
+        });
+        if explosible.is_some() {
+            let half_extent = collider.map_or(SMALL_EXPLOSION_EXTENT, |c| c.shape.radius() / 2.);
+            ev_explode.send(ExplosionEvent {
+                transform: *trans,
+                explosion: Explosion {
+                    half_extent,
+                    ..default()
+                },
+                sound: Some(if half_extent < SMALL_EXPLOSION_EXTENT {
+                    audio_asset.small_explosion.clone()
+                } else {
+                    audio_asset.explosion.clone()
+                }),
+            });
+        }
+    }
+}
+
+fn regenerate_shields(mut query: Query<&mut Shield>, time: Res<Time>) {
+    for mut shield in query.iter_mut() {
+        shield.since_hit += time.delta_secs();
+        if shield.since_hit >= shield.regen_delay && shield.current < shield.capacity {
+            shield.current =
+                (shield.current + shield.regen_rate * time.delta_secs()).min(shield.capacity);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use super::bots::{Bot, BotMarker, BotMotion, BotState, BotTurret};
 use super::camera::REAR_VIEW_LAYERS;
+use super::movement::RigidBody;
+use super::oct_tree::OctTree;
 use super::turret::*;
 use super::{spaceship::*, GameObjectMarker};
 use crate::asset_loader::*;
@@ -11,6 +13,9 @@ use bevy::prelude::*;
 use std::f32::INFINITY;
 use std::time::Duration;
 
+// obstacles further than this are not considered while steering
+const OBSTACLE_DETECTION_RANGE: f32 = 64.;
+
 #[derive(Component, Clone, Copy)]
 pub struct ObstacleMarker;
 
@@ -31,7 +36,7 @@ impl Plugin for DebugPlugin {
         )))
         // .add_systems(Startup, setup)
         .add_systems(
-            Update,
+            FixedUpdate,
             (detect_obstacle, avoid_obstacle)
                 .chain()
                 .in_set(UpdateSet::InGame), // .run_if(in_state(GameState::Game)),
@@ -69,15 +74,22 @@ fn mark_spaceship(
 
 fn avoid_obstacle(
     mut bot_query: Query<
-        (&mut Transform, &mut BotState, &mut BotMotion),
+        (
+            &mut Transform,
+            &mut BotState,
+            &mut BotMotion,
+            &mut RigidBody,
+        ),
         (With<BotMarker>, Without<SpaceShip>),
     >,
     planet_query: Query<&Transform, With<SpaceShip>>,
     time: Res<Time>,
     mut timer: ResMut<MyTimer>,
 ) {
-    for (mut trans, state, mut motion) in bot_query.iter_mut() {
-        let p_trans = planet_query.single();
+    let Ok(p_trans) = planet_query.get_single() else {
+        return;
+    };
+    for (mut trans, state, mut motion, mut body) in bot_query.iter_mut() {
         let t = time.delta_secs();
         match &*state {
             BotState::Chasing => {
@@ -138,18 +150,17 @@ fn avoid_obstacle(
                 );
                 trans.rotate(rotation);
                 motion.direction = trans.forward().as_vec3().normalize_or_zero();
-                trans.translation +=
-                    motion.velocity.clone().length() * motion.direction.clone() * t;
+                let speed = body.velocity.length();
+                body.accelerate_towards(speed * motion.direction, t, motion.acceleration);
             }
             BotState::Dodge(dir) => {
                 info!("dodging ");
                 let acc = motion.acceleration * dir.as_vec3();
-                motion.velocity += acc.clone() * t;
+                body.add_acceleration(acc);
                 *trans = trans.looking_to(
-                    Dir3::new(motion.velocity.clone().normalize_or_zero()).unwrap(),
-                    motion.velocity.clone().cross(dir.clone().as_vec3()),
+                    Dir3::new(body.velocity.normalize_or_zero()).unwrap(),
+                    body.velocity.cross(dir.clone().as_vec3()),
                 );
-                trans.translation += motion.velocity.clone() * t;
             }
             _ => (),
         }
@@ -159,17 +170,25 @@ fn avoid_obstacle(
 fn detect_obstacle(
     query: Query<(&Transform, &ObstacleInfo), With<ObstacleMarker>>,
     mut b_query: Query<
-        (Entity, &Transform, &mut BotMotion, &mut BotState),
+        (
+            Entity,
+            &Transform,
+            &mut BotMotion,
+            &RigidBody,
+            &mut BotState,
+        ),
         (With<BotMarker>, Without<ObstacleMarker>),
     >,
+    oct_tree: Res<OctTree>,
 ) {
-    for (entity, b_trans, mut motion, mut state) in b_query.iter_mut() {
+    for (entity, b_trans, mut motion, body, mut state) in b_query.iter_mut() {
         // store obstacle which is nearest on the collision path
         let mut obstacles: (f32, Dir3) = (f32::INFINITY, Dir3::Z); // placeholder value
-        for (p_trans, obstacle) in query.iter() {
+        let nearby = oct_tree.get_local_objects(b_trans.translation, OBSTACLE_DETECTION_RANGE);
+        for (p_trans, obstacle) in nearby.iter().filter_map(|e| query.get(*e).ok()) {
             let rad = obstacle.radius;
             let acc = motion.acceleration.clone();
-            let vel = motion.velocity.clone();
+            let vel = body.velocity;
             let p_pos = p_trans.translation.clone();
             let b_pos = b_trans.translation.clone();
             let b_dir = motion.direction.clone().normalize_or_zero();
@@ -0,0 +1,88 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+#[derive(Component, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Faction {
+    Player,
+    Bots,
+    Swarm,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Relationship {
+    // targeted and damaged
+    Hostile,
+    // not targeted but still damaged
+    Neutral,
+    // neither targeted nor damaged unless friendly fire is on
+    Allied,
+}
+
+// how factions treat each other, pairs missing from the table are neutral
+// and a faction is always allied with itself
+#[derive(Resource)]
+pub struct FactionTable {
+    relations: HashMap<(Faction, Faction), Relationship>,
+    pub friendly_fire: bool,
+}
+
+impl Default for FactionTable {
+    fn default() -> Self {
+        let mut table = Self {
+            relations: HashMap::new(),
+            friendly_fire: false,
+        };
+        table.set(Faction::Player, Faction::Bots, Relationship::Hostile);
+        table.set(Faction::Player, Faction::Swarm, Relationship::Hostile);
+        table.set(Faction::Bots, Faction::Swarm, Relationship::Allied);
+        table
+    }
+}
+
+impl FactionTable {
+    fn key(a: Faction, b: Faction) -> (Faction, Faction) {
+        if (a as u8) <= (b as u8) {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    pub fn set(&mut self, a: Faction, b: Faction, relationship: Relationship) {
+        self.relations.insert(Self::key(a, b), relationship);
+    }
+
+    pub fn relationship(&self, a: Faction, b: Faction) -> Relationship {
+        if a == b {
+            return Relationship::Allied;
+        }
+        self.relations
+            .get(&Self::key(a, b))
+            .copied()
+            .unwrap_or(Relationship::Neutral)
+    }
+
+    // entities without a faction are treated as hostile to everyone
+    pub fn is_hostile(&self, a: Option<&Faction>, b: Option<&Faction>) -> bool {
+        match (a, b) {
+            (Some(a), Some(b)) => self.relationship(*a, *b) == Relationship::Hostile,
+            _ => true,
+        }
+    }
+
+    pub fn can_damage(&self, attacker: Option<&Faction>, target: Option<&Faction>) -> bool {
+        match (attacker, target) {
+            (Some(a), Some(t)) => {
+                self.friendly_fire || self.relationship(*a, *t) != Relationship::Allied
+            }
+            _ => true,
+        }
+    }
+}
+
+pub struct FactionPlugin;
+impl Plugin for FactionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FactionTable>();
+    }
+}
@@ -0,0 +1,99 @@
+use super::movement::RigidBody;
+use crate::sets::*;
+use bevy::prelude::*;
+
+// keeps the pull finite for bodies right at the center of a source
+const MIN_GRAVITY_DISTANCE: f32 = 1.;
+
+// pulls every rigid body within radius towards the entity,
+// the acceleration is strength / distance^falloff so 2 is inverse square
+#[derive(Component, Clone, Copy)]
+pub struct GravitySource {
+    pub strength: f32,
+    pub radius: f32,
+    pub falloff: f32,
+}
+
+impl GravitySource {
+    pub fn acceleration(&self, source: Vec3, point: Vec3) -> Vec3 {
+        let to_source = source - point;
+        let distance = to_source.length();
+        if distance > self.radius {
+            return Vec3::ZERO;
+        }
+        let d = distance.max(MIN_GRAVITY_DISTANCE);
+        to_source.normalize_or_zero() * self.strength / d.powf(self.falloff)
+    }
+}
+
+pub struct GravityPlugin;
+impl Plugin for GravityPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(FixedUpdate, apply_gravity.in_set(UpdateSet::InGame));
+    }
+}
+
+fn apply_gravity(
+    sources: Query<(Entity, &GlobalTransform, &GravitySource)>,
+    mut bodies: Query<(Entity, &Transform, &mut RigidBody)>,
+) {
+    for (body_ent, trans, mut body) in bodies.iter_mut() {
+        let mut acceleration = Vec3::ZERO;
+        for (source_ent, gt, source) in sources.iter() {
+            if source_ent == body_ent {
+                continue;
+            }
+            acceleration += source.acceleration(gt.translation(), trans.translation);
+        }
+        body.add_acceleration(acceleration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::movement::integrate_rigid_bodies;
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+    use std::time::Duration;
+
+    // thrust of a bot flying straight ahead, as in BotState::Ideal
+    fn hold_course(mut query: Query<&mut RigidBody>, time: Res<Time>) {
+        for mut body in query.iter_mut() {
+            body.accelerate_towards(Vec3::X, time.delta_secs(), 5.);
+        }
+    }
+
+    #[test]
+    fn steered_bot_drifts_into_the_sun() {
+        let mut world = World::new();
+        world.insert_resource(Time::<()>::default());
+        world.spawn((
+            GlobalTransform::default(),
+            GravitySource {
+                strength: 400.,
+                radius: 80.,
+                falloff: 2.,
+            },
+        ));
+        let bot = world
+            .spawn((
+                Transform::from_xyz(0., 6., 0.),
+                RigidBody::new(1.).with_velocity(Vec3::X),
+            ))
+            .id();
+
+        for _ in 0..30 {
+            world
+                .resource_mut::<Time>()
+                .advance_by(Duration::from_secs_f32(1. / 60.));
+            world.run_system_once(apply_gravity).unwrap();
+            world.run_system_once(hold_course).unwrap();
+            world.run_system_once(integrate_rigid_bodies).unwrap();
+        }
+
+        let trans = world.get::<Transform>(bot).unwrap();
+        let body = world.get::<RigidBody>(bot).unwrap();
+        assert!(trans.translation.y < 5.5, "still at {}", trans.translation);
+        assert!(body.velocity.y < 0., "not falling, {}", body.velocity);
+    }
+}
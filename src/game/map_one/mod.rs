@@ -2,22 +2,34 @@ use bevy::prelude::*;
 use bevy::render::view::RenderLayers;
 use bevy::state::commands;
 use rand::Rng;
-use std::sync::{Arc, RwLock};
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::time::Duration;
 
-use super::debug::{ObstacleInfo, ObstacleMarker};
+use super::debug::ObstacleMarker;
 use super::missile::*;
 use super::GameObjectMarker;
 use crate::asset_loader::MapOneAssets;
 use crate::asset_loader::*;
+use crate::game::auto_collider::AutoCollider;
 use crate::game::camera::MAIN_CAMERA_LAYER;
 use crate::game::camera::{BACKGROUND_LAYER, NEBULA_LAYER};
-use crate::game::collider::{
-    ColliderInfo, ColliderMarker, ColliderType, CollisionDamage, SphericalCollider,
-};
+use crate::game::collider::{ColliderMarker, CollisionDamage, CollisionLayers, DamageMode};
+use crate::game::damage::DamageType;
+use crate::game::gravity::GravitySource;
+use crate::game::movement::TransformInterpolation;
+use crate::game::orbit::Orbit;
 use crate::sets::*;
 use crate::states::*;
 use bevy::pbr::*;
 
+// acceleration at unit distance, the pull falls off with the square of the distance
+const SUN_GRAVITY: f32 = 400.;
+const SUN_GRAVITY_RADIUS: f32 = 80.;
+const PLANET_GRAVITY: f32 = 100.;
+const PLANET_GRAVITY_RADIUS: f32 = 30.;
+const MOON_GRAVITY: f32 = 20.;
+const MOON_GRAVITY_RADIUS: f32 = 8.;
+
 pub struct MapOnePlugin;
 impl Plugin for MapOnePlugin {
     fn build(&self, app: &mut App) {
@@ -27,7 +39,13 @@ impl Plugin for MapOnePlugin {
         )
         .add_systems(
             Update,
-            (insert_emissive_property, revolve_satellites, spin_sun)
+            (insert_emissive_property, spin_sun)
+                .in_set(UpdateSet::InGame)
+                .run_if(in_state(GameState::Game)),
+        )
+        .add_systems(
+            FixedUpdate,
+            spin_satellites
                 .in_set(UpdateSet::InGame)
                 .run_if(in_state(GameState::Game)),
         )
@@ -109,11 +127,10 @@ fn setup_lights(mut commands: Commands) {
 }
 
 #[derive(Component)]
+#[require(TransformInterpolation)]
 pub struct Satellite {
-    angular_speed: f32,
-    distance: f32,
-    gravity: f32,
-    axis: Vec3,
+    // degrees per second around the local x axis
+    spin_speed: f32,
 }
 
 #[derive(Component)]
@@ -137,155 +154,169 @@ fn setup(
         SwarmMissileTarget,
     ));
     let dot_mesh = meshes.add(Sphere::new(0.02).mesh().ico(1).unwrap());
+    let planet_damage = CollisionDamage {
+        // the ship bounces off planets, so a graze should not be fatal
+        damage: 100.,
+        kind: DamageType::Collision,
+        from: None,
+        mode: DamageMode::Interval(Duration::from_millis(500)),
+    };
     let satellite_collider_obstacle = (
         ColliderMarker,
-        CollisionDamage {
-            damage: 1000.,
-            from: None,
-        },
+        planet_damage,
         GameObjectMarker,
         ObstacleMarker,
-        ObstacleInfo { radius: 8. },
-    );
-    commands.spawn((
-        Satellite {
-            angular_speed: 2.,
-            distance: 56.,
-            gravity: 0.,
-            axis: Vec3::Z,
+        GravitySource {
+            strength: PLANET_GRAVITY,
+            radius: PLANET_GRAVITY_RADIUS,
+            falloff: 2.,
         },
-        ColliderInfo {
-            collider_type: ColliderType::Sphere,
-            collider: Arc::new(RwLock::new(SphericalCollider {
-                radius: 8.,
-                center: Vec3::ZERO,
-            })),
-            immune_to: None,
-        },
-        satellite_collider_obstacle.clone(),
-        SatelliteMarker,
-        Transform::from_translation(Vec3::new(0., 56., 0.)).with_scale(Vec3::splat(4.)),
-        SceneRoot(map_assets.planet1.clone()),
-    ));
+    );
+    let planets = [
+        (
+            map_assets.planet1.clone(),
+            Orbit {
+                semi_major_axis: 56.,
+                inclination: FRAC_PI_2,
+                period: 180.,
+                mean_anomaly_at_epoch: FRAC_PI_2,
+                ..default()
+            },
+            8.,
+        ),
+        (
+            map_assets.planet2.clone(),
+            Orbit {
+                semi_major_axis: 64.,
+                // flipped plane, so it goes round the other way
+                inclination: PI,
+                period: 120.,
+                mean_anomaly_at_epoch: PI,
+                ..default()
+            },
+            -12.,
+        ),
+        (
+            map_assets.planet3.clone(),
+            Orbit {
+                semi_major_axis: 84.,
+                eccentricity: 0.2,
+                inclination: FRAC_PI_2,
+                period: 180.,
+                mean_anomaly_at_epoch: 1.14,
+                ..default()
+            },
+            8.,
+        ),
+        (
+            map_assets.planet4.clone(),
+            Orbit {
+                semi_major_axis: 156.,
+                eccentricity: 0.1,
+                inclination: FRAC_PI_2,
+                ascending_node: FRAC_PI_2,
+                period: 360.,
+                ..default()
+            },
+            4.,
+        ),
+        (
+            map_assets.planet5.clone(),
+            Orbit {
+                semi_major_axis: 176.,
+                eccentricity: 0.05,
+                inclination: FRAC_PI_2,
+                ascending_node: -FRAC_PI_2,
+                period: 360.,
+                mean_anomaly_at_epoch: PI,
+                ..default()
+            },
+            -4.,
+        ),
+        (
+            map_assets.planet6.clone(),
+            Orbit {
+                semi_major_axis: 128.,
+                inclination: 0.1,
+                period: 360.,
+                mean_anomaly_at_epoch: 1.27,
+                ..default()
+            },
+            4.,
+        ),
+        (
+            map_assets.planet7.clone(),
+            Orbit {
+                semi_major_axis: 136.,
+                inclination: FRAC_PI_2,
+                period: 360.,
+                mean_anomaly_at_epoch: 4.6,
+                ..default()
+            },
+            4.,
+        ),
+    ];
+    let planet_ids: Vec<Entity> = planets
+        .iter()
+        .map(|(scene, orbit, spin_speed)| {
+            commands
+                .spawn((
+                    *orbit,
+                    Satellite {
+                        spin_speed: *spin_speed,
+                    },
+                    AutoCollider::sphere(CollisionLayers::environment()),
+                    satellite_collider_obstacle.clone(),
+                    SatelliteMarker,
+                    Transform::from_translation(orbit.local_position(0.))
+                        .with_scale(Vec3::splat(4.)),
+                    SceneRoot(scene.clone()),
+                ))
+                .id()
+        })
+        .collect();
+
+    // a moon around the eccentric planet
+    let moon_orbit = Orbit {
+        parent: Some(planet_ids[2]),
+        semi_major_axis: 14.,
+        eccentricity: 0.1,
+        inclination: 0.4,
+        period: 20.,
+        ..default()
+    };
     commands.spawn((
-        Satellite {
-            angular_speed: -3.0,
-            distance: 64.,
-            gravity: 0.,
-            axis: Vec3::Y,
-        },
-        ColliderInfo {
-            collider_type: ColliderType::Sphere,
-            collider: Arc::new(RwLock::new(SphericalCollider {
-                radius: 8.,
-                center: Vec3::ZERO,
-            })),
-            immune_to: None,
+        moon_orbit,
+        Satellite { spin_speed: 20. },
+        AutoCollider::sphere(CollisionLayers::environment()),
+        ColliderMarker,
+        planet_damage,
+        GameObjectMarker,
+        ObstacleMarker,
+        GravitySource {
+            strength: MOON_GRAVITY,
+            radius: MOON_GRAVITY_RADIUS,
+            falloff: 2.,
         },
-        satellite_collider_obstacle.clone(),
         SatelliteMarker,
-        Transform::from_translation(Vec3::new(-64., 0., 0.)).with_scale(Vec3::splat(4.)),
+        Transform::from_translation(
+            planets[2].1.local_position(0.) + moon_orbit.local_position(0.),
+        ),
         SceneRoot(map_assets.planet2.clone()),
     ));
+    // and a ring that stays on the first planet
     commands.spawn((
-        Satellite {
-            angular_speed: 2.0,
-            distance: 84.,
-            gravity: 0.,
-            axis: Vec3::Z,
-        },
-        ColliderInfo {
-            collider_type: ColliderType::Sphere,
-            collider: Arc::new(RwLock::new(SphericalCollider {
-                radius: 2.,
-                center: Vec3::ZERO,
-            })),
-            immune_to: None,
-        },
-        satellite_collider_obstacle.clone(),
-        SatelliteMarker,
-        Transform::from_translation(Vec3::new(40., 86., 0.)).with_scale(Vec3::splat(4.)),
-        SceneRoot(map_assets.planet3.clone()),
-    ));
-    commands.spawn((
-        Satellite {
-            angular_speed: 1.,
-            distance: 156.,
-            gravity: 0.,
-            axis: Vec3::X,
-        },
-        ColliderInfo {
-            collider_type: ColliderType::Sphere,
-            collider: Arc::new(RwLock::new(SphericalCollider {
-                radius: 8.,
-                center: Vec3::ZERO,
-            })),
-            immune_to: None,
-        },
-        satellite_collider_obstacle.clone(),
-        SatelliteMarker,
-        Transform::from_translation(Vec3::new(-144., 60., 0.)).with_scale(Vec3::splat(4.)),
-        SceneRoot(map_assets.planet4.clone()),
-    ));
-    commands.spawn((
-        Satellite {
-            angular_speed: -1.,
-            distance: 156.,
-            gravity: 0.,
-            axis: Vec3::X,
-        },
-        ColliderInfo {
-            collider_type: ColliderType::Sphere,
-            collider: Arc::new(RwLock::new(SphericalCollider {
-                radius: 8.,
-                center: Vec3::ZERO,
-            })),
-            immune_to: None,
-        },
-        satellite_collider_obstacle.clone(),
-        SatelliteMarker,
-        Transform::from_translation(Vec3::new(16., 40., 128.)).with_scale(Vec3::splat(4.)),
-        SceneRoot(map_assets.planet5.clone()),
-    ));
-    commands.spawn((
-        Satellite {
-            angular_speed: 1.,
-            distance: 128.,
-            gravity: 0.,
-            axis: Vec3::Y,
-        },
-        ColliderInfo {
-            collider_type: ColliderType::Sphere,
-            collider: Arc::new(RwLock::new(SphericalCollider {
-                radius: 8.,
-                center: Vec3::ZERO,
-            })),
-            immune_to: None,
-        },
-        satellite_collider_obstacle.clone(),
-        SatelliteMarker,
-        Transform::from_translation(Vec3::new(40., -16., -128.)).with_scale(Vec3::splat(4.)),
-        SceneRoot(map_assets.planet6.clone()),
-    ));
-    commands.spawn((
-        Satellite {
-            angular_speed: 1.,
-            distance: 136.,
-            gravity: 0.,
-            axis: Vec3::Z,
-        },
-        ColliderInfo {
-            collider_type: ColliderType::Sphere,
-            collider: Arc::new(RwLock::new(SphericalCollider {
-                radius: 8.,
-                center: Vec3::ZERO,
-            })),
-            immune_to: None,
-        },
-        satellite_collider_obstacle.clone(),
-        SatelliteMarker,
-        Transform::from_translation(Vec3::new(-16., -128., 40.)).with_scale(Vec3::splat(4.)),
-        SceneRoot(map_assets.planet7.clone()),
+        Orbit::circular(Some(planet_ids[0]), 0., 1.),
+        Mesh3d(meshes.add(Torus::new(10., 12.))),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::srgba(0.8, 0.7, 0.6, 0.6),
+            alpha_mode: AlphaMode::Blend,
+            ..default()
+        })),
+        GameObjectMarker,
+        Transform::from_translation(planets[0].1.local_position(0.))
+            .with_rotation(Quat::from_rotation_z(0.4))
+            .with_scale(Vec3::new(1., 0.05, 1.)),
     ));
 
     commands.spawn((
@@ -316,7 +347,19 @@ fn setup(
         },
         Name::new("Sun"),
         ObstacleMarker,
-        ObstacleInfo { radius: 18. },
+        GravitySource {
+            strength: SUN_GRAVITY,
+            radius: SUN_GRAVITY_RADIUS,
+            falloff: 2.,
+        },
+        // anything pulled in burns up
+        AutoCollider::sphere(CollisionLayers::environment()),
+        CollisionDamage {
+            damage: 1000.,
+            kind: DamageType::Collision,
+            from: None,
+            mode: DamageMode::Interval(Duration::from_millis(500)),
+        },
         PointLight {
             color: Color::LinearRgba(LinearRgba {
                 red: 255.,
@@ -403,16 +446,13 @@ fn spin_sun(mut query: Query<&mut Transform, With<SunMarker>>, time: Res<Time>)
     }
 }
 
-fn revolve_satellites(
+// the orbit itself is moved by the orbit plugin
+fn spin_satellites(
     mut query: Query<(&Satellite, &mut Transform), With<SatelliteMarker>>,
     time: Res<Time>,
 ) {
     for (sat, mut trans) in query.iter_mut() {
-        trans.rotate_around(
-            Vec3::ZERO,
-            Quat::from_axis_angle(sat.axis, sat.angular_speed.to_radians() * time.delta_secs()),
-        );
-        trans.rotate_local_x(sat.angular_speed.to_radians() * 4. * time.delta_secs());
+        trans.rotate_local_x(sat.spin_speed.to_radians() * time.delta_secs());
     }
 }
 
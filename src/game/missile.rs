@@ -5,20 +5,26 @@ use bevy::audio::{SpatialScale, Volume};
 use bevy::{prelude::*, state::commands};
 
 use super::collider::{
-    collision_response, ColliderInfo, ColliderMarker, ColliderType, CollisionDamage,
-    SphericalCollider,
+    CapsuleCollider, ColliderInfo, ColliderMarker, ColliderShape, CollisionDamage, CollisionLayers,
+    DamageMode, SphericalCollider, SweptCollider,
 };
+use super::damage::{DamageType, DestroyedWriter, EntityDestroyed};
 use super::explosion::ExplosibleObjectMarker;
+use super::faction::Faction;
+use super::movement::RigidBody;
 use super::spaceship::Health;
 use super::GameObjectMarker;
 use crate::asset_loader::{AudioAssets, SceneAssets};
 use crate::sets::UpdateSet;
 use std::f32::consts::PI;
-use std::sync::{Arc, RwLock};
 
 const HOMING_MISSILE_DAMAGE: f32 = 100.;
 const SWARM_MISSILE_DAMAGE: f32 = 20.;
 const MISSILE_DESTRUCT_TIME: f32 = 5.;
+const MISSILE_MASS: f32 = 1.;
+const HOMING_MISSILE_DRAG: f32 = 1.;
+// enough to follow the speed curve, gravity close to the sun still wins
+const SWARM_MISSILE_THRUST: f32 = 40.;
 
 const MISSILE_OFFSET: Transform = Transform::from_xyz(0., 0., 0.);
 
@@ -99,8 +105,8 @@ pub struct Missile {
     pub thrust: f32,
     pub timer: Duration,
     pub damage: f32,
+    // inherited from the launcher, the body takes over after launch
     pub velocity: Vec3,
-    pub drag: Vec3,
     pub angular_speed: f32,
 }
 
@@ -113,7 +119,7 @@ impl Plugin for MissilePlugin {
         app.add_event::<HomingMissileShootEvent>()
             .add_event::<SwarmMissileShootEvent>()
             .add_systems(
-                Update,
+                FixedUpdate,
                 (
                     launch_homing_missile,
                     launch_swarm_missile,
@@ -121,16 +127,6 @@ impl Plugin for MissilePlugin {
                     move_missile,
                 )
                     .in_set(UpdateSet::InGame),
-            )
-            .add_systems(
-                Update,
-                (
-                    collision_response::<HomingMissileMarker>,
-                    collision_response::<SwarmMissileMarker>,
-                    despawn_swarm_missile,
-                )
-                    .chain()
-                    .in_set(UpdateSet::InGame),
             );
     }
 }
@@ -141,12 +137,14 @@ fn launch_swarm_missile(
     query: Query<(&GlobalTransform, &SwarmMissileLauncher), With<SwarmMissileLauncher>>,
     scene_asset: Res<SceneAssets>,
     audio_asset: Res<AudioAssets>,
+    faction_query: Query<&Faction>,
 ) {
     for SwarmMissileShootEvent { launcher, missile } in ev_swarm_missile.read() {
         if let Ok((gt, swarm_missile_launcher)) = query.get(*launcher) {
             let transform = gt.compute_transform();
             let bundle = (
                 missile.clone(),
+                RigidBody::new(MISSILE_MASS),
                 GameObjectMarker,
                 SwarmMissileMarker,
                 Health(1.),
@@ -161,16 +159,21 @@ fn launch_swarm_missile(
                 ColliderMarker,
                 ExplosibleObjectMarker,
                 ColliderInfo {
-                    collider_type: ColliderType::Sphere,
-                    collider: Arc::new(RwLock::new(SphericalCollider {
+                    shape: ColliderShape::Sphere(SphericalCollider {
                         center: Vec3::ZERO,
                         radius: 0.02,
-                    })),
-                    immune_to: Some(Vec::from([swarm_missile_launcher.source.unwrap()])),
+                    }),
+                    // only the player carries launchers
+                    layers: CollisionLayers::player_projectile(),
                 },
                 CollisionDamage {
                     damage: 100.,
+                    kind: DamageType::Explosive,
                     from: swarm_missile_launcher.source,
+                    mode: DamageMode::OnEnter,
+                },
+                SweptCollider {
+                    previous_center: transform.translation,
                 },
                 transform,
                 SceneRoot(scene_asset.missile.clone()),
@@ -184,7 +187,11 @@ fn launch_swarm_missile(
                 },
             );
             commands.spawn(sound_effect);
-            commands.spawn(bundle);
+            let mut missile_entity = commands.spawn(bundle);
+            // the shooter may be gone by the time the missile hits
+            if let Some(Ok(faction)) = swarm_missile_launcher.source.map(|s| faction_query.get(s)) {
+                missile_entity.insert(*faction);
+            }
         }
     }
 }
@@ -195,12 +202,16 @@ fn launch_homing_missile(
     mut commands: Commands,
     scene_asset: Res<SceneAssets>,
     audio_asset: Res<AudioAssets>,
+    faction_query: Query<&Faction>,
 ) {
     for HomingMissileShootEvent { launcher, missile } in ev_homing.read() {
         if let Ok((gt, homing_launcher)) = query.get(*launcher) {
             let transform = gt.compute_transform();
             let bundle = (
                 missile.clone(),
+                RigidBody::new(MISSILE_MASS)
+                    .with_drag(HOMING_MISSILE_DRAG, 0.)
+                    .with_velocity(missile.velocity),
                 GameObjectMarker,
                 HomingMissileMarker,
                 MissileMarker,
@@ -217,16 +228,22 @@ fn launch_homing_missile(
                 ColliderMarker,
                 ExplosibleObjectMarker,
                 ColliderInfo {
-                    collider_type: ColliderType::Sphere,
-                    collider: Arc::new(RwLock::new(SphericalCollider {
-                        center: Vec3::ZERO,
+                    shape: ColliderShape::Capsule(CapsuleCollider {
                         radius: 0.05,
-                    })),
-                    immune_to: Some(Vec::from([homing_launcher.source.unwrap()])),
+                        half_length: 0.15,
+                        center: Vec3::ZERO,
+                        rotation: Quat::IDENTITY,
+                    }),
+                    layers: CollisionLayers::player_projectile(),
                 },
                 CollisionDamage {
                     damage: 1000.,
+                    kind: DamageType::Explosive,
                     from: homing_launcher.source,
+                    mode: DamageMode::OnEnter,
+                },
+                SweptCollider {
+                    previous_center: transform.translation,
                 },
                 transform,
                 SceneRoot(scene_asset.missile2.clone()),
@@ -240,21 +257,21 @@ fn launch_homing_missile(
                 },
             );
             commands.spawn(sound_effect);
-            commands.spawn(bundle);
+            let mut missile_entity = commands.spawn(bundle);
+            if let Some(Ok(faction)) = homing_launcher.source.map(|s| faction_query.get(s)) {
+                missile_entity.insert(*faction);
+            }
         }
     }
 }
 
 fn move_missile(
-    mut query: Query<(Entity, &mut Transform, &mut Missile, &Health), With<MissileMarker>>,
+    mut query: Query<(Entity, &mut Transform, &mut Missile, &mut RigidBody), With<MissileMarker>>,
     t_query: Query<&Transform, (With<HomingMissileTarget>, Without<MissileMarker>)>,
     time: Res<Time>,
-    mut commands: Commands,
+    mut ev_destroyed: DestroyedWriter,
 ) {
-    for (ent, mut trans, mut missile, heatlh) in query.iter_mut() {
-        if heatlh.0 <= 0. {
-            commands.entity(ent).despawn_recursive();
-        }
+    for (ent, mut trans, mut missile, mut body) in query.iter_mut() {
         if missile.is_locked {
             if let Ok(t_trans) = t_query.get(missile.target.unwrap()) {
                 let dir_vec = t_trans.translation - trans.translation;
@@ -272,28 +289,32 @@ fn move_missile(
 
         let spin_axis = trans.forward();
         // trans.rotate_axis(spin_axis, PI * 2. * time.delta_secs());
-        missile.velocity = missile.velocity
-            + ((missile.thrust * trans.forward().as_vec3()) + missile.drag) * time.delta_secs();
-        missile.drag = -missile.velocity;
-        trans.translation += missile.velocity * time.delta_secs();
+        body.add_acceleration(missile.thrust * trans.forward().as_vec3());
         missile.timer += time.delta();
         if missile.timer.as_secs_f32() > MISSILE_DESTRUCT_TIME {
-            commands.entity(ent).despawn();
+            ev_destroyed.send(EntityDestroyed {
+                entity: ent,
+                killer: None,
+                position: trans.translation,
+            });
         }
     }
 }
 
 fn move_swarm_missile(
     mut query: Query<
-        (Entity, &mut Transform, &mut SwarmMissile, &mut Health),
+        (Entity, &mut Transform, &mut SwarmMissile, &mut RigidBody),
         With<SwarmMissileMarker>,
     >,
     t_query: Query<&Transform, (With<SwarmMissileTarget>, Without<SwarmMissileMarker>)>,
     time: Res<Time>,
     mut commands: Commands,
+    mut ev_destroyed: DestroyedWriter,
 ) {
-    for (ent, mut s_trans, mut missile, mut health) in query.iter_mut() {
+    for (ent, mut s_trans, mut missile, mut body) in query.iter_mut() {
         let stage = missile.stage.clone();
+        // reached the converge point or ran out of time
+        let mut spent = false;
         missile.timer = missile.timer + time.delta();
         'block1: {
             match stage {
@@ -340,7 +361,7 @@ fn move_swarm_missile(
                 SwarmMissileStage::Stage2(None, point) => {
                     let vec_dir = point - s_trans.translation;
                     if vec_dir.length_squared() < 0.01 {
-                        health.0 = 0.;
+                        spent = true;
                     }
                     let axis = s_trans
                         .forward()
@@ -357,23 +378,18 @@ fn move_swarm_missile(
             }
         }
         s_trans.rotate_local_z(PI * 4.0 * time.delta_secs());
-        s_trans.translation = s_trans.translation
-            + (missile.speed + missile.initial_speed)
-                * s_trans.forward().as_vec3()
-                * time.delta_secs();
-        if missile.timer.as_secs_f32() > 2.0 {
-            health.0 = 0.0;
-        }
-    }
-}
-
-fn despawn_swarm_missile(
-    mut commands: Commands,
-    query: Query<(Entity, &Health), With<SwarmMissileMarker>>,
-) {
-    for (ent, health) in query.iter() {
-        if health.0 <= 0.0 {
-            commands.entity(ent).despawn_recursive();
+        // speed follows a fixed curve, so steer the body onto it
+        body.accelerate_towards(
+            (missile.speed + missile.initial_speed) * s_trans.forward().as_vec3(),
+            time.delta_secs(),
+            SWARM_MISSILE_THRUST,
+        );
+        if spent || missile.timer.as_secs_f32() > 2.0 {
+            ev_destroyed.send(EntityDestroyed {
+                entity: ent,
+                killer: None,
+                position: s_trans.translation,
+            });
         }
     }
 }
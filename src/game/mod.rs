@@ -1,19 +1,28 @@
+pub mod auto_collider;
 pub mod bots;
 pub mod camera;
 pub mod collider;
+pub mod damage;
 pub mod debug;
 pub mod environment;
 pub mod explosion;
+pub mod faction;
+pub mod gravity;
 pub mod hud;
 pub mod map_one;
 pub mod mesh;
 pub mod missile;
 pub mod movement;
 pub mod obstacle;
-mod oct_tree;
+pub mod oct_tree;
+pub mod orbit;
 pub mod pause_menu;
 pub mod spaceship;
+pub mod spatial_query;
 pub mod swarm;
+pub mod swarm_behavior;
+pub mod swarm_formation;
+pub mod swarm_membership;
 mod terrain;
 pub mod turret;
 
@@ -27,17 +36,23 @@ use crate::states::{GameState, MenuState};
 use crate::{events::TurretEventPlugin, states::InGameStates};
 use bevy::prelude::*;
 // use bevy_inspector_egui::egui::menu::MenuState;
+use auto_collider::AutoColliderPlugin;
 use bots::BotPlugin;
 use camera::CameraPlugin;
-use collider::{Collider, ColliderInfo, ColliderMarker, ColliderPlugin, ColliderType};
+use collider::ColliderPlugin;
+use damage::{DamagePlugin, EntityDestroyed};
 use debug::DebugPlugin;
 use environment::LandscapePlugin;
 use explosion::ExplosionPlugin;
+use faction::FactionPlugin;
+use gravity::GravityPlugin;
 use map_one::MapOnePlugin;
 use mesh::TestMeshPlugin;
 use missile::MissilePlugin;
+use movement::MovementPlugin;
 use obstacle::ObstaclePlugin;
-use oct_tree::{NodeEntities, OctTree, OctTreePlugin};
+use oct_tree::{OctTree, OctTreePlugin};
+use orbit::OrbitPlugin;
 use pause_menu::PauseMenuPlugin;
 use spaceship::SpaceShipPlugin;
 use swarm::SwarmPlugin;
@@ -60,6 +75,12 @@ impl Plugin for GamePlugin {
         .add_plugins(CameraPlugin)
         .add_plugins(SpaceShipPlugin)
         .add_plugins(ColliderPlugin)
+        .add_plugins(AutoColliderPlugin)
+        .add_plugins(MovementPlugin)
+        .add_plugins(GravityPlugin)
+        .add_plugins(OrbitPlugin)
+        .add_plugins(DamagePlugin)
+        .add_plugins(FactionPlugin)
         .add_plugins(SwarmPlugin)
         // .add_plugins(ObstaclePlugin);
         // .add_plugins(TestMeshPlugin);
@@ -68,19 +89,47 @@ impl Plugin for GamePlugin {
         .add_plugins(MissilePlugin)
         .add_plugins(MapOnePlugin)
         // .add_plugins(TerrainPlugin)
-        // .add_plugins(OctTreePlugin);
+        .add_plugins(OctTreePlugin)
         .add_plugins(ExplosionPlugin)
         .add_plugins(PauseMenuPlugin)
+        // rendering side of the game, camera, effects and input
         .configure_sets(
             Update,
             UpdateSet::InGame
                 .run_if(in_state(GameState::Game))
                 .run_if(in_state(InGameStates::Play)),
         )
+        // the simulation, movement, ai, collisions and damage tick at PhysicsConfig::tick_rate
+        .configure_sets(
+            FixedUpdate,
+            UpdateSet::InGame
+                .run_if(in_state(GameState::Game))
+                .run_if(in_state(InGameStates::Play)),
+        )
         // .configure_sets(
         //     OnEnter(InGameStates::Restart),
         //     SetupSet::InGame.run_if(in_state(GameState::Game)),
         // )
+        // forces added during UpdateSet::InGame are integrated here
+        .configure_sets(
+            FixedUpdate,
+            PhysicsSet::Integrate
+                .after(UpdateSet::InGame)
+                .run_if(in_state(GameState::Game))
+                .run_if(in_state(InGameStates::Play)),
+        )
+        // systems reacting to EntityDestroyed run here, before the entities are despawned
+        .configure_sets(
+            FixedUpdate,
+            (
+                DespawnSet::InGame(InGameSet::SpaceShip),
+                DespawnSet::InGame(InGameSet::Bots),
+                DespawnSet::InGame(InGameSet::Swarm),
+            )
+                .after(UpdateSet::InGame)
+                .run_if(in_state(GameState::Game))
+                .run_if(in_state(InGameStates::Play)),
+        )
         .configure_sets(
             OnEnter(InGameStates::Setup),
             SetupSet::InGame.run_if(in_state(GameState::Game)),
@@ -97,6 +146,14 @@ impl Plugin for GamePlugin {
         //         .in_set(SetupSet::InGame)
         //         .run_if(in_state(GameState::Game)),
         // )
+        .add_systems(
+            FixedUpdate,
+            despawn_destroyed
+                .after(DespawnSet::InGame(InGameSet::SpaceShip))
+                .after(DespawnSet::InGame(InGameSet::Bots))
+                .after(DespawnSet::InGame(InGameSet::Swarm))
+                .run_if(in_state(GameState::Game)),
+        )
         .add_systems(OnEnter(GameState::Game), setup)
         .add_systems(
             Update,
@@ -121,6 +178,14 @@ fn setup(mut commands: Commands) {
     commands.insert_resource(GameOverTimeOut(Timer::from_seconds(3.0, TimerMode::Once)));
 }
 
+fn despawn_destroyed(mut commands: Commands, mut ev_destroyed: EventReader<EntityDestroyed>) {
+    for ev in ev_destroyed.read() {
+        if let Some(entity) = commands.get_entity(ev.entity) {
+            entity.despawn_recursive();
+        }
+    }
+}
+
 fn start_game(mut in_game_state: ResMut<NextState<InGameStates>>) {
     in_game_state.set(InGameStates::Play);
 }
@@ -147,29 +212,8 @@ fn visualize_oct_tree(
     mut meshes: ResMut<Assets<Mesh>>,
     mut gizmos: Gizmos,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    query: Query<(Entity, &GlobalTransform, &ColliderInfo), With<ColliderMarker>>,
+    oct_tree: Res<OctTree>,
 ) {
-    let mut oct_tree = OctTree::default();
-    for (entity, gt, ci) in query.iter() {
-        let mut radius: f32 = 0.;
-        let center = gt.translation();
-        match ci.collider_type {
-            ColliderType::Sphere => {
-                radius = ci.collider.read().unwrap().get_radius().unwrap();
-                oct_tree
-                    .pending_insertions
-                    .write()
-                    .unwrap()
-                    .push(NodeEntities {
-                        entity,
-                        center,
-                        radius,
-                    });
-            }
-            _ => (),
-        }
-    }
-    oct_tree.build_tree();
     let mut q: VecDeque<OctNode> = VecDeque::new();
     q.push_back(oct_tree.root.as_ref().clone());
     let mat = MeshMaterial3d(materials.add(StandardMaterial {
@@ -1,22 +1,229 @@
+use crate::sets::*;
+use bevy::app::RunFixedMainLoopSystem;
 use bevy::prelude::*;
-#[derive(Component, Default)]
-pub struct Velocity(pub Vec3);
 
-#[derive(Component, Default)]
-pub struct Thrust(pub Vec3);
+const DEFAULT_TICK_RATE: f64 = 60.;
 
-#[derive(Component)]
-pub struct Drag(pub Vec3);
+// rate of the FixedUpdate simulation in ticks per second
+#[derive(Resource)]
+pub struct PhysicsConfig {
+    pub tick_rate: f64,
+}
 
-#[derive(Component, Default)]
-pub struct Inertia {
-    pub velocity: Velocity,
-    pub thrust: f32,
-    pub angular_velocity: f32,
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        Self {
+            tick_rate: DEFAULT_TICK_RATE,
+        }
+    }
 }
 
 #[derive(Component, Default)]
 pub struct Direction(pub Vec3, pub Vec3);
 
-#[derive(Component)]
-pub struct Position(pub Vec3);
+// every moving entity feeds forces into its body during UpdateSet::InGame,
+// they are integrated once per fixed tick in PhysicsSet::Integrate and then cleared
+#[derive(Component, Clone, Copy)]
+#[require(TransformInterpolation)]
+pub struct RigidBody {
+    pub mass: f32,
+    // resistance to torque, the same about every axis
+    pub inertia: f32,
+    pub velocity: Vec3,
+    // axis scaled by radians per second
+    pub angular_velocity: Vec3,
+    // force against the velocity per unit of speed
+    pub linear_drag: f32,
+    pub angular_drag: f32,
+    force: Vec3,
+    torque: Vec3,
+    impulse: Vec3,
+}
+
+impl Default for RigidBody {
+    fn default() -> Self {
+        Self {
+            mass: 1.,
+            inertia: 1.,
+            velocity: Vec3::ZERO,
+            angular_velocity: Vec3::ZERO,
+            linear_drag: 0.,
+            angular_drag: 0.,
+            force: Vec3::ZERO,
+            torque: Vec3::ZERO,
+            impulse: Vec3::ZERO,
+        }
+    }
+}
+
+impl RigidBody {
+    pub fn new(mass: f32) -> Self {
+        Self {
+            mass,
+            inertia: mass,
+            ..default()
+        }
+    }
+
+    pub fn with_inertia(mut self, inertia: f32) -> Self {
+        self.inertia = inertia;
+        self
+    }
+
+    pub fn with_drag(mut self, linear: f32, angular: f32) -> Self {
+        self.linear_drag = linear;
+        self.angular_drag = angular;
+        self
+    }
+
+    pub fn with_velocity(mut self, velocity: Vec3) -> Self {
+        self.velocity = velocity;
+        self
+    }
+
+    pub fn add_force(&mut self, force: Vec3) {
+        self.force += force;
+    }
+
+    // same push regardless of mass
+    pub fn add_acceleration(&mut self, acceleration: Vec3) {
+        self.force += acceleration * self.mass;
+    }
+
+    pub fn add_torque(&mut self, torque: Vec3) {
+        self.torque += torque;
+    }
+
+    // instant change of momentum, applied on the next integration
+    pub fn apply_impulse(&mut self, impulse: Vec3) {
+        self.impulse += impulse;
+    }
+
+    // steers the body towards the given velocity, drag included, with at most
+    // max_acceleration of thrust. gravity stronger than that pulls the body off course
+    pub fn accelerate_towards(&mut self, velocity: Vec3, dt: f32, max_acceleration: f32) {
+        if dt <= 0. {
+            return;
+        }
+        let force = self.mass * (velocity - self.velocity) / dt + self.linear_drag * self.velocity;
+        self.force += force.clamp_length_max(self.mass * max_acceleration.max(0.));
+    }
+}
+
+// the transform is simulated in FixedUpdate and drawn between the last two ticks,
+// anything moved by the simulation but without a RigidBody should carry this too
+#[derive(Component, Clone, Copy, Default)]
+pub struct TransformInterpolation {
+    previous: Option<Transform>,
+    current: Option<Transform>,
+}
+
+pub struct MovementPlugin;
+impl Plugin for MovementPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PhysicsConfig>()
+            .add_systems(
+                Update,
+                apply_tick_rate.run_if(resource_changed::<PhysicsConfig>),
+            )
+            .add_systems(
+                FixedUpdate,
+                integrate_rigid_bodies.in_set(PhysicsSet::Integrate),
+            )
+            .add_systems(FixedLast, record_simulated_transforms)
+            .add_systems(
+                RunFixedMainLoop,
+                (
+                    restore_simulated_transforms
+                        .in_set(RunFixedMainLoopSystem::BeforeFixedMainLoop),
+                    interpolate_transforms.in_set(RunFixedMainLoopSystem::AfterFixedMainLoop),
+                ),
+            );
+    }
+}
+
+fn apply_tick_rate(config: Res<PhysicsConfig>, mut time: ResMut<Time<Fixed>>) {
+    time.set_timestep_hz(config.tick_rate);
+}
+
+pub(super) fn integrate_rigid_bodies(
+    mut query: Query<(&mut Transform, &mut RigidBody)>,
+    time: Res<Time>,
+) {
+    let dt = time.delta_secs();
+    for (mut trans, mut body) in query.iter_mut() {
+        let body = &mut *body;
+        let inv_mass = if body.mass > 0. { 1. / body.mass } else { 0. };
+        let drag = -body.velocity * body.linear_drag;
+        let inv_inertia = if body.inertia > 0. {
+            1. / body.inertia
+        } else {
+            0.
+        };
+        let angular_drag = -body.angular_velocity * body.angular_drag;
+        body.velocity =
+            body.velocity + body.impulse * inv_mass + (body.force + drag) * inv_mass * dt;
+        body.angular_velocity += (body.torque + angular_drag) * inv_inertia * dt;
+        trans.translation += body.velocity * dt;
+        if body.angular_velocity != Vec3::ZERO {
+            trans.rotate(Quat::from_scaled_axis(body.angular_velocity * dt));
+        }
+        body.force = Vec3::ZERO;
+        body.torque = Vec3::ZERO;
+        body.impulse = Vec3::ZERO;
+    }
+}
+
+// put back the simulated transform before the ticks of this frame run.
+// global transforms of root entities are kept in step as well, since colliders
+// and the oct tree read them and propagation only happens once per frame
+fn restore_simulated_transforms(
+    mut query: Query<(
+        &mut Transform,
+        &mut GlobalTransform,
+        &TransformInterpolation,
+        Has<Parent>,
+    )>,
+) {
+    for (mut trans, mut gt, interpolation, has_parent) in query.iter_mut() {
+        if let Some(current) = interpolation.current {
+            *trans = current;
+            if !has_parent {
+                *gt = current.into();
+            }
+        }
+    }
+}
+
+fn record_simulated_transforms(
+    mut query: Query<(
+        &Transform,
+        &mut GlobalTransform,
+        &mut TransformInterpolation,
+        Has<Parent>,
+    )>,
+) {
+    for (trans, mut gt, mut interpolation, has_parent) in query.iter_mut() {
+        interpolation.previous = interpolation.current.or(Some(*trans));
+        interpolation.current = Some(*trans);
+        if !has_parent {
+            *gt = (*trans).into();
+        }
+    }
+}
+
+fn interpolate_transforms(
+    mut query: Query<(&mut Transform, &TransformInterpolation)>,
+    time: Res<Time<Fixed>>,
+) {
+    let alpha = time.overstep_fraction();
+    for (mut trans, interpolation) in query.iter_mut() {
+        let (Some(previous), Some(current)) = (interpolation.previous, interpolation.current)
+        else {
+            continue;
+        };
+        trans.translation = previous.translation.lerp(current.translation, alpha);
+        trans.rotation = previous.rotation.slerp(current.rotation, alpha);
+        trans.scale = previous.scale.lerp(current.scale, alpha);
+    }
+}
@@ -1,15 +1,118 @@
+use super::collider::{
+    segment_hits_sphere, BoxCollider, ColliderInfo, ColliderMarker, SweptCollider,
+};
+use super::debug::{ObstacleInfo, ObstacleMarker};
+use crate::states::{GameState, InGameStates};
 use bevy::prelude::*;
+use bevy::tasks::{ComputeTaskPool, TaskPool};
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
 const ROOT_HALF_LENGTH: f32 = 128.;
 const ROOT_CENTER: Vec3 = Vec3::splat(0.);
 const SPLIT_POINT: u32 = 5;
 const MINIMUM_HL: f32 = 0.0625;
+// the root stops growing here, anything further out is kept on the root
+const MAXIMUM_ROOT_HL: f32 = 8192.;
 
 pub struct OctTreePlugin;
 impl Plugin for OctTreePlugin {
     fn build(&self, app: &mut App) {
-        // app.add_systems(PostStartup, print_landscape);
+        // updated before every fixed tick so the whole tick queries the same tree
+        app.init_resource::<OctTree>()
+            .init_resource::<OctTreeRemovals>()
+            .add_systems(OnEnter(InGameStates::Setup), reset_oct_tree)
+            .add_systems(Last, buffer_oct_tree_removals)
+            .add_systems(
+                FixedPreUpdate,
+                update_oct_tree
+                    .run_if(in_state(GameState::Game))
+                    .run_if(in_state(InGameStates::Play)),
+            );
+    }
+}
+
+// removal events only live for a couple of frames, frames without a fixed tick
+// would drop them, so they are kept here until the next tick
+#[derive(Resource, Default)]
+struct OctTreeRemovals(Vec<Entity>);
+
+fn reset_oct_tree(mut oct_tree: ResMut<OctTree>, mut removals: ResMut<OctTreeRemovals>) {
+    *oct_tree = OctTree::default();
+    removals.0.clear();
+}
+
+fn buffer_oct_tree_removals(
+    mut removals: ResMut<OctTreeRemovals>,
+    mut removed_colliders: RemovedComponents<ColliderMarker>,
+    mut removed_obstacles: RemovedComponents<ObstacleMarker>,
+) {
+    removals
+        .0
+        .extend(removed_colliders.read().chain(removed_obstacles.read()));
+}
+
+fn update_oct_tree(
+    mut oct_tree: ResMut<OctTree>,
+    query: Query<
+        (
+            Entity,
+            Ref<GlobalTransform>,
+            Option<Ref<ColliderInfo>>,
+            Option<&ObstacleInfo>,
+            Option<&SweptCollider>,
+        ),
+        Or<(With<ColliderMarker>, With<ObstacleMarker>)>,
+    >,
+    mut removals: ResMut<OctTreeRemovals>,
+    mut removed_colliders: RemovedComponents<ColliderMarker>,
+    mut removed_obstacles: RemovedComponents<ObstacleMarker>,
+) {
+    // removals of this frame that happened before the tick are not buffered yet
+    let removed = removals
+        .0
+        .drain(..)
+        .chain(removed_colliders.read())
+        .chain(removed_obstacles.read());
+    for entity in removed {
+        // still indexed if only one of the markers was removed
+        if !query.contains(entity) {
+            oct_tree.remove(entity);
+        }
+    }
+    for (entity, gt, ci, obstacle, swept) in query.iter() {
+        let ci_changed = ci.as_ref().is_some_and(|ci| ci.is_changed());
+        if !gt.is_changed() && !ci_changed && oct_tree.contains(entity) {
+            continue;
+        }
+        let mut radius: f32 = 0.;
+        if let Some(ci) = ci {
+            radius = ci.shape.radius();
+        }
+        if let Some(obstacle) = obstacle {
+            radius = radius.max(obstacle.radius);
+        }
+        let mut center = gt.translation();
+        // swept colliders are indexed with the whole path they cover this frame
+        if let Some(swept) = swept {
+            center = (swept.previous_center + gt.translation()) / 2.;
+            radius += (gt.translation() - swept.previous_center).length() / 2.;
+        }
+        oct_tree
+            .pending_insertions
+            .write()
+            .unwrap()
+            .push(NodeEntities {
+                entity,
+                center,
+                radius,
+            });
+    }
+    // filling an empty tree is the expensive case, build it on all cores
+    if oct_tree.is_empty() {
+        oct_tree.build_tree_parallel();
+    } else {
+        oct_tree.update_tree();
     }
 }
 
@@ -55,10 +158,13 @@ pub struct OctNode {
 
 type Root = OctNode;
 
+#[derive(Resource)]
 pub struct OctTree {
     pub root: Box<Root>,
     children_mask: Arc<ChildrenMask>,
     pub pending_insertions: RwLock<Vec<NodeEntities>>,
+    // last inserted state of every object, used to find it again when it moves
+    entities: HashMap<Entity, NodeEntities>,
 }
 
 impl OctNode {
@@ -91,19 +197,29 @@ impl OctNode {
         }
     }
 
+    // index of the only child that can hold the object, none if it has to stay on this node
+    fn bounding_child(&self, obj: &NodeEntities) -> Option<usize> {
+        if self.on_parent(obj) {
+            return None;
+        }
+        let children = self.children.as_ref()?;
+        (0..8).find(|&i| children[i].as_ref().read().unwrap().is_bounding(obj))
+    }
+
     fn rearrange_objects_to_children(&mut self) {
         let mut parent_object = Vec::<NodeEntities>::new();
-        for obj in &self.objects {
-            if self.on_parent(obj) {
-                parent_object.push(obj.clone());
-            } else {
+        let objects = std::mem::take(&mut self.objects);
+        for obj in &objects {
+            if let Some(i) = self.bounding_child(obj) {
                 let children = self.children.as_mut().unwrap();
-                for i in 0..8 {
-                    let mut child = children[i].as_ref().write().unwrap();
-                    if child.is_bounding(obj) {
-                        child.objects.push(obj.clone());
-                    }
-                }
+                children[i]
+                    .as_ref()
+                    .write()
+                    .unwrap()
+                    .objects
+                    .push(obj.clone());
+            } else {
+                parent_object.push(obj.clone());
             }
         }
         self.objects = parent_object;
@@ -120,18 +236,87 @@ impl OctNode {
                 self.insert(obj);
             }
         } else {
-            if self.on_parent(obj) {
-                self.objects.push(obj.clone());
+            if let Some(i) = self.bounding_child(obj) {
+                let children = self.children.as_mut().unwrap();
+                children[i].as_ref().write().unwrap().insert(obj);
             } else {
-                for i in 0..8 {
-                    let children = self.children.as_mut().unwrap();
-                    let mut child = children[i].as_ref().write().unwrap();
-                    if child.is_bounding(obj) {
-                        child.insert(obj);
-                    }
+                self.objects.push(obj.clone());
+            }
+        }
+    }
+
+    fn remove(&mut self, obj: &NodeEntities) -> bool {
+        if let Some(i) = self.objects.iter().position(|o| o.entity == obj.entity) {
+            self.objects.remove(i);
+            return true;
+        }
+        let mut removed = false;
+        if let Some(children) = &self.children {
+            for child in children {
+                let mut child = child.as_ref().write().unwrap();
+                // objects are always bounded by the node holding them
+                if child.distance_to_point(obj.center) <= obj.radius && child.remove(obj) {
+                    removed = true;
+                    break;
                 }
             }
         }
+        if removed {
+            self.collapse();
+        }
+        removed
+    }
+
+    // turns the node back into a leaf once its children are leaves with few objects left
+    fn collapse(&mut self) {
+        let Some(children) = &self.children else {
+            return;
+        };
+        let mut count = self.objects.len();
+        for child in children {
+            let child = child.as_ref().read().unwrap();
+            if !child.is_leaf_node {
+                return;
+            }
+            count += child.objects.len();
+        }
+        if count >= SPLIT_POINT as usize {
+            return;
+        }
+        for child in self.children.take().unwrap() {
+            self.objects
+                .extend(child.as_ref().read().unwrap().objects.iter().copied());
+        }
+        self.is_leaf_node = true;
+    }
+
+    // an object can only overlap objects on its own node, on the nodes above it
+    // (they straddle this one) or on the nodes below it
+    fn collect_pairs(
+        &self,
+        ancestors: &mut Vec<NodeEntities>,
+        pairs: &mut Vec<(NodeEntities, NodeEntities)>,
+    ) {
+        for (i, obj) in self.objects.iter().enumerate() {
+            for other in &self.objects[i + 1..] {
+                pairs.push((*obj, *other));
+            }
+            for other in ancestors.iter() {
+                pairs.push((*other, *obj));
+            }
+        }
+        if let Some(children) = &self.children {
+            let len = ancestors.len();
+            ancestors.extend_from_slice(&self.objects);
+            for child in children {
+                child
+                    .as_ref()
+                    .read()
+                    .unwrap()
+                    .collect_pairs(ancestors, pairs);
+            }
+            ancestors.truncate(len);
+        }
     }
 
     fn build_children(&mut self) -> Result<(), String> {
@@ -162,23 +347,339 @@ impl OctNode {
 }
 
 impl OctTree {
-    pub fn update_tree(&self) {
-        // if self.pending_insertions
-        todo!();
+    // inserts, moves or re-inserts everything pending
+    pub fn update_tree(&mut self) {
+        let pending = std::mem::take(self.pending_insertions.get_mut().unwrap());
+        for obj in pending {
+            self.insert(obj);
+        }
     }
 
+    // drops the current tree and builds a new one out of the pending objects
     pub fn build_tree(&mut self) {
-        for obj in self.pending_insertions.read().unwrap().iter() {
-            self.root.insert(obj);
+        for obj in self.prepare_build() {
+            self.insert(obj);
+        }
+    }
+
+    // same tree as build_tree, the octants of the root are filled on the compute task pool
+    pub fn build_tree_parallel(&mut self) {
+        let objects = self.prepare_build();
+        for obj in objects.iter() {
+            self.entities.insert(obj.entity, *obj);
+        }
+
+        // replay what the root does during a sequential build, only its children are deferred
+        let root = &mut self.root;
+        let mut buckets: Vec<Vec<NodeEntities>> = vec![Vec::new(); 8];
+        for obj in objects {
+            if !root.is_bounding(&obj) {
+                root.objects.push(obj);
+                continue;
+            }
+            if root.is_leaf_node {
+                if root.objects.len() < SPLIT_POINT as usize || root.half_length <= MINIMUM_HL {
+                    root.objects.push(obj);
+                    continue;
+                }
+                root.is_leaf_node = false;
+                let _ = root.build_children();
+                for o in std::mem::take(&mut root.objects) {
+                    match root.bounding_child(&o) {
+                        Some(i) => buckets[i].push(o),
+                        None => root.objects.push(o),
+                    }
+                }
+            }
+            match root.bounding_child(&obj) {
+                Some(i) => buckets[i].push(obj),
+                None => root.objects.push(obj),
+            }
+        }
+
+        if let Some(children) = &root.children {
+            let pool = ComputeTaskPool::get_or_init(TaskPool::new);
+            pool.scope(|scope| {
+                for (child, bucket) in children.iter().zip(buckets) {
+                    scope.spawn(async move {
+                        let mut child = child.as_ref().write().unwrap();
+                        for obj in bucket.iter() {
+                            child.insert(obj);
+                        }
+                    });
+                }
+            });
+        }
+    }
+
+    // takes the pending objects, resets the tree and sizes the root so they all fit
+    fn prepare_build(&mut self) -> Vec<NodeEntities> {
+        let pending = std::mem::take(self.pending_insertions.get_mut().unwrap());
+        // only the last state of an entity is kept
+        let mut last = HashMap::<Entity, usize>::new();
+        for (i, obj) in pending.iter().enumerate() {
+            last.insert(obj.entity, i);
+        }
+        let objects: Vec<NodeEntities> = pending
+            .into_iter()
+            .enumerate()
+            .filter(|(i, obj)| last[&obj.entity] == *i)
+            .map(|(_, obj)| obj)
+            .collect();
+
+        *self = OctTree::default();
+        for obj in objects.iter() {
+            while !self.root.is_bounding(obj) && self.root.half_length < MAXIMUM_ROOT_HL {
+                let hl = self.root.half_length;
+                let dir = obj.center - self.root.center;
+                self.root.center = OctNode::new_center(&self.root.center, Self::grow_mask(dir), hl);
+                self.root.half_length = hl * 2.;
+            }
+        }
+        objects
+    }
+
+    fn grow_mask(dir: Vec3) -> Vec3 {
+        Vec3::new(
+            if dir.x >= 0. { 1. } else { -1. },
+            if dir.y >= 0. { 1. } else { -1. },
+            if dir.z >= 0. { 1. } else { -1. },
+        )
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.entities.contains_key(&entity)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn insert(&mut self, obj: NodeEntities) {
+        if let Some(old) = self.entities.get(&obj.entity).copied() {
+            if old.center == obj.center && old.radius == obj.radius {
+                return;
+            }
+            self.root.remove(&old);
+        }
+        self.grow_to_fit(&obj);
+        if self.root.is_bounding(&obj) {
+            self.root.insert(&obj);
+        } else {
+            self.root.objects.push(obj);
+        }
+        self.entities.insert(obj.entity, obj);
+    }
+
+    pub fn remove(&mut self, entity: Entity) -> bool {
+        if let Some(old) = self.entities.remove(&entity) {
+            self.root.remove(&old)
+        } else {
+            false
         }
     }
+
+    // doubles the root towards the object until it fits, the old root becomes one of the children
+    fn grow_to_fit(&mut self, obj: &NodeEntities) {
+        if self.root.is_bounding(obj) || self.root.half_length >= MAXIMUM_ROOT_HL {
+            return;
+        }
+        // objects already sticking out of the root can't be moved down with it
+        let objects = std::mem::take(&mut self.root.objects);
+        let (inside, outliers): (Vec<NodeEntities>, Vec<NodeEntities>) =
+            objects.into_iter().partition(|o| self.root.is_bounding(o));
+        self.root.objects = inside;
+
+        while !self.root.is_bounding(obj) && self.root.half_length < MAXIMUM_ROOT_HL {
+            let hl = self.root.half_length;
+            let mask = Self::grow_mask(obj.center - self.root.center);
+            let mut root = OctNode::new(
+                hl * 2.,
+                OctNode::new_center(&self.root.center, mask, hl),
+                SPLIT_POINT,
+                Arc::clone(&self.children_mask),
+                MINIMUM_HL,
+            );
+            let _ = root.build_children();
+            let i = self
+                .children_mask
+                .0
+                .iter()
+                .position(|m| *m == -mask)
+                .unwrap();
+            let old_root = std::mem::replace(&mut self.root, Box::new(root));
+            self.root.children.as_mut().unwrap()[i] = Arc::new(RwLock::new(*old_root));
+        }
+
+        for o in outliers {
+            if self.root.is_bounding(&o) {
+                self.root.insert(&o);
+            } else {
+                self.root.objects.push(o);
+            }
+        }
+    }
+
+    // broadphase, pairs of objects which share a node or sit on nodes along the same branch
+    pub fn get_candidate_pairs(&self) -> Vec<(NodeEntities, NodeEntities)> {
+        let mut pairs = Vec::new();
+        self.root.collect_pairs(&mut Vec::new(), &mut pairs);
+        pairs
+    }
+    // entities whose bounding sphere touches the given sphere
     pub fn get_local_objects(&self, center: Vec3, radius: f32) -> Vec<Entity> {
-        let result: Vec<Entity> = Vec::new();
-        todo!();
+        let mut result = Vec::<NodeEntities>::new();
+        self.root.collect_in_radius(center, radius, &mut result);
+        result.iter().map(|obj| obj.entity).collect()
+    }
+
+    // entities whose bounding sphere comes within radius of the segment
+    pub fn get_along_segment(&self, from: Vec3, to: Vec3, radius: f32) -> Vec<Entity> {
+        let mut result = Vec::<NodeEntities>::new();
+        self.root
+            .collect_along_segment(from, to, radius, &mut result);
+        result.iter().map(|obj| obj.entity).collect()
+    }
+
+    // k closest entities to the point passing the filter, sorted by distance
+    pub fn get_k_nearest(
+        &self,
+        point: Vec3,
+        k: usize,
+        filter: impl Fn(Entity) -> bool,
+    ) -> Vec<(Entity, f32)> {
+        let mut best = Vec::<(Entity, f32)>::with_capacity(k + 1);
+        if k > 0 {
+            self.root.collect_nearest(point, k, &filter, &mut best);
+        }
+        best
+    }
+
+    // entities within range whose center lies inside the cone, sorted by distance
+    pub fn get_in_cone(
+        &self,
+        apex: Vec3,
+        dir: Vec3,
+        half_angle: f32,
+        range: f32,
+    ) -> Vec<(Entity, f32)> {
+        let dir = dir.normalize_or_zero();
+        let mut objects = Vec::<NodeEntities>::new();
+        self.root.collect_in_radius(apex, range, &mut objects);
+        let mut result: Vec<(Entity, f32)> = objects
+            .iter()
+            .filter_map(|obj| {
+                let diff = obj.center - apex;
+                let dist = diff.length();
+                // no acos, rounding can push the cosine past 1 right on the axis.
+                // an object at the apex has no direction and is left out
+                if dist > 0. && dist <= range && dir.dot(diff) >= dist * half_angle.cos() {
+                    Some((obj.entity, dist))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        result.sort_by(|a, b| a.1.total_cmp(&b.1));
         result
     }
 }
 
+impl OctNode {
+    // 0 when the point is inside the node
+    fn distance_to_point(&self, p: Vec3) -> f32 {
+        let d = (p - self.center).abs() - Vec3::splat(self.half_length);
+        d.max(Vec3::ZERO).length()
+    }
+
+    fn collect_in_radius(&self, center: Vec3, radius: f32, result: &mut Vec<NodeEntities>) {
+        for obj in &self.objects {
+            if (obj.center - center).length() <= radius + obj.radius {
+                result.push(*obj);
+            }
+        }
+        if let Some(children) = &self.children {
+            for child in children {
+                let child = child.as_ref().read().unwrap();
+                if child.distance_to_point(center) <= radius {
+                    child.collect_in_radius(center, radius, result);
+                }
+            }
+        }
+    }
+
+    fn collect_along_segment(
+        &self,
+        from: Vec3,
+        to: Vec3,
+        radius: f32,
+        result: &mut Vec<NodeEntities>,
+    ) {
+        for obj in &self.objects {
+            if segment_hits_sphere(from, to, obj.center, obj.radius + radius) {
+                result.push(*obj);
+            }
+        }
+        if let Some(children) = &self.children {
+            for child in children {
+                let child = child.as_ref().read().unwrap();
+                let bounds = BoxCollider {
+                    half_extents: Vec3::splat(child.half_length),
+                    center: child.center,
+                    rotation: Quat::IDENTITY,
+                };
+                if bounds.segment_hits(from, to, radius) {
+                    child.collect_along_segment(from, to, radius, result);
+                }
+            }
+        }
+    }
+
+    fn collect_nearest(
+        &self,
+        point: Vec3,
+        k: usize,
+        filter: &impl Fn(Entity) -> bool,
+        best: &mut Vec<(Entity, f32)>,
+    ) {
+        for obj in &self.objects {
+            if !filter(obj.entity) {
+                continue;
+            }
+            let dist = (obj.center - point).length();
+            if best.len() == k && dist >= best[k - 1].1 {
+                continue;
+            }
+            let at = best.partition_point(|(_, d)| *d <= dist);
+            best.insert(at, (obj.entity, dist));
+            best.truncate(k);
+        }
+        if let Some(children) = &self.children {
+            // visit the closest children first so the far ones can be pruned
+            let mut order: Vec<(f32, &Arc<RwLock<OctNode>>)> = children
+                .iter()
+                .map(|child| {
+                    (
+                        child.as_ref().read().unwrap().distance_to_point(point),
+                        child,
+                    )
+                })
+                .collect();
+            order.sort_by(|a, b| a.0.total_cmp(&b.0));
+            for (dist, child) in order {
+                if best.len() == k && dist > best[k - 1].1 {
+                    break;
+                }
+                child
+                    .as_ref()
+                    .read()
+                    .unwrap()
+                    .collect_nearest(point, k, filter, best);
+            }
+        }
+    }
+}
+
 impl OctNode {
     fn new(
         half_length: f32,
@@ -222,6 +723,101 @@ impl Default for OctTree {
             pending_insertions: RwLock::new(Vec::<NodeEntities>::with_capacity(
                 SPLIT_POINT as usize,
             )),
+            entities: HashMap::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashSet;
+
+    fn seeded_objects(count: u32) -> Vec<NodeEntities> {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..count)
+            .map(|i| NodeEntities {
+                entity: Entity::from_raw(i),
+                center: Vec3::new(
+                    rng.random_range(-300.0..300.0),
+                    rng.random_range(-300.0..300.0),
+                    rng.random_range(-300.0..300.0),
+                ),
+                radius: rng.random_range(0.05..4.0),
+            })
+            .collect()
+    }
+
+    fn built(objects: &[NodeEntities], parallel: bool) -> OctTree {
+        let mut tree = OctTree::default();
+        tree.pending_insertions
+            .write()
+            .unwrap()
+            .extend_from_slice(objects);
+        if parallel {
+            tree.build_tree_parallel();
+        } else {
+            tree.build_tree();
+        }
+        tree
+    }
+
+    // every node with the entities it holds
+    fn membership(node: &OctNode, out: &mut Vec<(Vec3, f32, Vec<Entity>)>) {
+        let mut entities: Vec<Entity> = node.objects.iter().map(|obj| obj.entity).collect();
+        entities.sort();
+        out.push((node.center, node.half_length, entities));
+        for child in node.children.iter().flatten() {
+            membership(&child.read().unwrap(), out);
+        }
+    }
+
+    fn pairs(tree: &OctTree) -> HashSet<(Entity, Entity)> {
+        tree.get_candidate_pairs()
+            .into_iter()
+            .map(|(a, b)| (a.entity.min(b.entity), a.entity.max(b.entity)))
+            .collect()
+    }
+
+    #[test]
+    fn parallel_build_matches_sequential_build() {
+        let objects = seeded_objects(2000);
+        let sequential = built(&objects, false);
+        let parallel = built(&objects, true);
+
+        let (mut seq_nodes, mut par_nodes) = (Vec::new(), Vec::new());
+        membership(&sequential.root, &mut seq_nodes);
+        membership(&parallel.root, &mut par_nodes);
+        assert_eq!(seq_nodes, par_nodes);
+        assert!(objects
+            .iter()
+            .all(|obj| sequential.contains(obj.entity) && parallel.contains(obj.entity)));
+        assert_eq!(pairs(&sequential), pairs(&parallel));
+    }
+
+    #[test]
+    fn cone_keeps_objects_on_its_axis() {
+        let apex = Vec3::new(1.1, 2.3, -0.7);
+        let dir = Vec3::new(0.3, 0.4, 1.2);
+        let mut tree = OctTree::default();
+        for (i, distance) in [0., 0.7, 3.3, 9.1].into_iter().enumerate() {
+            tree.insert(NodeEntities {
+                entity: Entity::from_raw(i as u32),
+                center: apex + dir.normalize() * distance,
+                radius: 0.1,
+            });
+        }
+        let found: Vec<Entity> = tree
+            .get_in_cone(apex, dir, 0.01, 10.)
+            .into_iter()
+            .map(|(e, _)| e)
+            .collect();
+        assert_eq!(
+            found,
+            [1, 2, 3].map(Entity::from_raw).to_vec(),
+            "on the axis in distance order, the object at the apex left out"
+        );
+    }
+}
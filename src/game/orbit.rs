@@ -0,0 +1,244 @@
+use super::movement::TransformInterpolation;
+use crate::sets::*;
+use crate::states::*;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use std::f32::consts::TAU;
+
+// parent chains deeper than this are treated as broken
+const MAX_ORBIT_DEPTH: usize = 8;
+const KEPLER_ITERATIONS: usize = 8;
+
+// keplerian orbit around the parent body, or around the origin without one.
+// the reference plane is xz with y up, inclination tilts it around x and
+// ascending_node turns the tilt around y, angles are in radians
+#[derive(Component, Clone, Copy)]
+#[require(TransformInterpolation)]
+pub struct Orbit {
+    pub parent: Option<Entity>,
+    pub semi_major_axis: f32,
+    // 0 is a circle, has to stay below 1
+    pub eccentricity: f32,
+    pub inclination: f32,
+    pub ascending_node: f32,
+    pub argument_of_periapsis: f32,
+    // seconds per revolution
+    pub period: f32,
+    // where on the orbit the body is at t = 0
+    pub mean_anomaly_at_epoch: f32,
+}
+
+impl Default for Orbit {
+    fn default() -> Self {
+        Self {
+            parent: None,
+            semi_major_axis: 0.,
+            eccentricity: 0.,
+            inclination: 0.,
+            ascending_node: 0.,
+            argument_of_periapsis: 0.,
+            period: 1.,
+            mean_anomaly_at_epoch: 0.,
+        }
+    }
+}
+
+impl Orbit {
+    pub fn circular(parent: Option<Entity>, radius: f32, period: f32) -> Self {
+        Self {
+            parent,
+            semi_major_axis: radius,
+            period,
+            ..default()
+        }
+    }
+
+    // the orbital plane with the periapsis along +x
+    pub fn plane_rotation(&self) -> Quat {
+        Quat::from_rotation_y(self.ascending_node)
+            * Quat::from_rotation_x(self.inclination)
+            * Quat::from_rotation_y(self.argument_of_periapsis)
+    }
+
+    // offset from the parent at time t
+    pub fn local_position(&self, t: f32) -> Vec3 {
+        let e = self.eccentricity.clamp(0., 0.99);
+        let mean_anomaly = self.mean_anomaly_at_epoch + TAU * t / self.period;
+        let ecc_anomaly = solve_kepler(mean_anomaly.rem_euclid(TAU), e);
+        let x = self.semi_major_axis * (ecc_anomaly.cos() - e);
+        let y = self.semi_major_axis * (1. - e * e).sqrt() * ecc_anomaly.sin();
+        // counter clockwise seen from above the plane
+        self.plane_rotation() * Vec3::new(x, 0., -y)
+    }
+}
+
+// eccentric anomaly for the mean anomaly, newton on M = E - e sin E
+fn solve_kepler(mean_anomaly: f32, e: f32) -> f32 {
+    let mut ecc_anomaly = if e > 0.8 {
+        std::f32::consts::PI
+    } else {
+        mean_anomaly
+    };
+    for _ in 0..KEPLER_ITERATIONS {
+        let f = ecc_anomaly - e * ecc_anomaly.sin() - mean_anomaly;
+        ecc_anomaly -= f / (1. - e * ecc_anomaly.cos());
+    }
+    ecc_anomaly
+}
+
+// seconds of simulated play, orbits are evaluated at this time
+#[derive(Resource, Default)]
+pub struct OrbitClock(pub f32);
+
+// where orbiting bodies are at any time, follows the parent chain
+#[derive(SystemParam)]
+pub struct OrbitQuery<'w, 's> {
+    orbits: Query<'w, 's, &'static Orbit>,
+    clock: Res<'w, OrbitClock>,
+}
+
+impl OrbitQuery<'_, '_> {
+    pub fn now(&self) -> f32 {
+        self.clock.0
+    }
+
+    pub fn position_at(&self, entity: Entity, t: f32) -> Option<Vec3> {
+        orbit_position_at(&self.orbits, entity, t)
+    }
+
+    // position after the given number of seconds from now
+    pub fn predict(&self, entity: Entity, seconds: f32) -> Option<Vec3> {
+        self.position_at(entity, self.clock.0 + seconds)
+    }
+}
+
+pub fn orbit_position_at(orbits: &Query<&Orbit>, entity: Entity, t: f32) -> Option<Vec3> {
+    let mut position = Vec3::ZERO;
+    let mut current = entity;
+    for _ in 0..MAX_ORBIT_DEPTH {
+        let orbit = orbits.get(current).ok()?;
+        position += orbit.local_position(t);
+        match orbit.parent {
+            Some(parent) => current = parent,
+            None => return Some(position),
+        }
+    }
+    None
+}
+
+pub struct OrbitPlugin;
+impl Plugin for OrbitPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<OrbitClock>()
+            .add_systems(OnEnter(InGameStates::Setup), reset_orbit_clock)
+            .add_systems(FixedUpdate, move_orbiting_bodies.in_set(UpdateSet::InGame));
+    }
+}
+
+fn reset_orbit_clock(mut clock: ResMut<OrbitClock>) {
+    clock.0 = 0.;
+}
+
+fn move_orbiting_bodies(
+    mut query: Query<(Entity, &mut Transform), With<Orbit>>,
+    orbits: Query<&Orbit>,
+    mut clock: ResMut<OrbitClock>,
+    time: Res<Time>,
+) {
+    clock.0 += time.delta_secs();
+    for (entity, mut trans) in query.iter_mut() {
+        if let Some(position) = orbit_position_at(&orbits, entity, clock.0) {
+            trans.translation = position;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+    use std::f32::consts::PI;
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!(a.abs_diff_eq(b, 1e-3), "{a} is not {b}");
+    }
+
+    #[test]
+    fn kepler_solution_satisfies_the_equation() {
+        assert_eq!(solve_kepler(1.2, 0.), 1.2);
+        for e in [0.1, 0.5, 0.9] {
+            for mean_anomaly in [0.3, 1., PI, 4., 6.] {
+                let ecc_anomaly = solve_kepler(mean_anomaly, e);
+                let residual = ecc_anomaly - e * ecc_anomaly.sin() - mean_anomaly;
+                assert!(residual.abs() < 1e-4, "e {e} mean anomaly {mean_anomaly}");
+            }
+        }
+    }
+
+    #[test]
+    fn circular_orbit_turns_a_quarter_in_a_quarter_period() {
+        let orbit = Orbit::circular(None, 10., 4.);
+        assert_near(orbit.local_position(0.), Vec3::X * 10.);
+        assert_near(orbit.local_position(1.), Vec3::NEG_Z * 10.);
+        assert_near(orbit.local_position(2.), Vec3::NEG_X * 10.);
+        assert_near(orbit.local_position(4.), Vec3::X * 10.);
+    }
+
+    #[test]
+    fn eccentric_orbit_is_closest_at_periapsis_and_furthest_at_apoapsis() {
+        let orbit = Orbit {
+            semi_major_axis: 10.,
+            eccentricity: 0.5,
+            period: 4.,
+            ..default()
+        };
+        assert_near(orbit.local_position(0.), Vec3::X * 5.);
+        assert_near(orbit.local_position(2.), Vec3::NEG_X * 15.);
+        // the same orbit turned so the periapsis points along -z
+        let turned = Orbit {
+            argument_of_periapsis: PI / 2.,
+            ..orbit
+        };
+        assert_near(turned.local_position(0.), Vec3::NEG_Z * 5.);
+    }
+
+    #[test]
+    fn moon_orbits_around_where_its_planet_is() {
+        let mut world = World::new();
+        let planet_orbit = Orbit::circular(None, 10., 4.);
+        let moon_orbit = Orbit {
+            eccentricity: 0.2,
+            ..Orbit::circular(None, 2., 1.5)
+        };
+        let planet = world.spawn(planet_orbit).id();
+        let moon = world
+            .spawn(Orbit {
+                parent: Some(planet),
+                ..moon_orbit
+            })
+            .id();
+        // a body that ends up orbiting itself
+        let broken = world.spawn_empty().id();
+        world
+            .entity_mut(broken)
+            .insert(Orbit::circular(Some(broken), 1., 1.));
+
+        let t = 1.3;
+        let (planet_at, moon_at, broken_at) = world
+            .run_system_once(move |orbits: Query<&Orbit>| {
+                (
+                    orbit_position_at(&orbits, planet, t),
+                    orbit_position_at(&orbits, moon, t),
+                    orbit_position_at(&orbits, broken, t),
+                )
+            })
+            .unwrap();
+
+        assert_near(planet_at.unwrap(), planet_orbit.local_position(t));
+        assert_near(
+            moon_at.unwrap(),
+            planet_orbit.local_position(t) + moon_orbit.local_position(t),
+        );
+        assert_eq!(broken_at, None);
+    }
+}
@@ -1,10 +1,14 @@
+use super::auto_collider::AutoCollider;
 use super::bots::*;
 use super::collider::*;
+use super::damage::{DamageType, EntityDestroyed, Shield};
 use super::explosion::ExplosibleObjectMarker;
 use super::explosion::{Explosion, ExplosionEvent};
+use super::faction::{Faction, FactionTable};
 use super::missile::Missile;
 use super::missile::{HomingMissileShootEvent, HomingMissileTarget, *};
-use super::movement::{Direction, Drag, Inertia, Position};
+use super::movement::{Direction, RigidBody};
+use super::oct_tree::OctTree;
 use super::swarm;
 use super::swarm::*;
 use super::turret::*;
@@ -27,19 +31,19 @@ use bevy::utils::info;
 use rand::Rng;
 use std::f32::consts::PI;
 use std::fmt::Error;
-use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 const DEFAULT_HEALTH: f32 = 1000.0;
+const PLAYER_SHIELD: f32 = 50.;
 const DEFAULT_THRUST: Vec3 = Vec3::new(0.5, 0.5, 0.5);
-const DEFAULT_SPAWN: Vec3 = Vec3::ZERO;
 const DEFAULT_ANGULAR_CHANGE: f32 = 50.0;
 const DEFAULT_STEERING_BOOST: f32 = 30.;
 const DEFAULT_ROLL_BOOST: f32 = 60.;
 const DEFAULT_THRUST_LIMIT: f32 = 10.0;
 const DEFAULT_ROLL_ANGULAR_CHANGE: f32 = 100.0;
 const DEFAULT_DIRECTION: (Vec3, Vec3) = (Vec3::Y, Vec3::X);
-const DEFAULT_DRAG: Vec3 = Vec3::new(0.0, 0.0, 0.0);
+const DEFAULT_MASS: f32 = 1.;
+const DEFAULT_DRAG: f32 = 2.;
 const DEFAULT_SPEED_LIMIT: f32 = 1.5;
 const DEFAULT_ROLL_THRUST: f32 = 180.;
 // const SWARM_MISSILE_TIMER: Timer = Timer::new(Duration::from_millis(125), TimerMode::Repeating);
@@ -80,6 +84,10 @@ pub struct SpaceShipTurret;
 #[derive(Component)]
 pub struct Health(pub f32);
 
+// forward acceleration set by the throttle keys
+#[derive(Component, Default)]
+pub struct Throttle(pub f32);
+
 impl Health {
     fn new(x: f32) -> Self {
         Self(x)
@@ -139,18 +147,29 @@ impl SpaceShipHomingTarget {
     }
 }
 
+// key presses seen in Update, held until a fixed tick consumes them so a press is
+// neither missed nor repeated when there are fewer or more ticks than frames
+#[derive(Resource, Default)]
+pub struct PlayerInput {
+    thrust: bool,
+    back_thrust: bool,
+    shoot_released: bool,
+    missile_switch: bool,
+    missile_shoot: bool,
+    missile_aim_released: bool,
+}
+
 #[derive(Bundle)]
 pub struct SpaceShipBundle {
     pub health: Health,
     pub marker: SpaceShip,
-    pub position: Position,
-    pub inertia: Inertia,
+    pub throttle: Throttle,
+    pub body: RigidBody,
     pub direction: Direction,
     pub model: SceneRoot,
     pub transform: Transform,
     pub playback_settings: PlaybackSettings,
     pub throttle_audio: AudioPlayer,
-    pub drag: Drag,
 }
 pub struct SpaceShipPlugin;
 
@@ -164,21 +183,31 @@ impl Plugin for SpaceShipPlugin {
         .add_systems(
             Update,
             (
-                aim_homing,
-                draw_aim_lock,
-                collision_response::<SpaceShip>,
-                spaceship_controls
+                buffer_player_input
                     .in_set(InputSet::InGame(ControlsSet::InGame(InGameSet::SpaceShip))),
-                accelerate_spaceship,
-                move_spaceship,
-                spaceship_orientation,
-                missile_control,
-                shoot_turret::<SpaceShipTurret>, // adjust_drag,
-                game_over,
+                draw_aim_lock,
             )
                 // .after(SetupSet::InGame)
                 .chain()
                 .in_set(UpdateSet::InGame), // .run_if(in_state(GameState::InGame(InGameStates::Play))), // .after(SetupSet::InGame(InGameSet::SpaceShip)),
+        )
+        .add_systems(
+            FixedUpdate,
+            (
+                aim_homing,
+                spaceship_controls,
+                missile_control,
+                shoot_turret::<SpaceShipTurret>, // adjust_drag,
+                accelerate_spaceship,
+                spaceship_orientation,
+                clear_player_input,
+            )
+                .chain()
+                .in_set(UpdateSet::InGame),
+        )
+        .add_systems(
+            FixedUpdate,
+            game_over.in_set(DespawnSet::InGame(InGameSet::SpaceShip)),
         );
         // .add_systems(
         //     Update,
@@ -187,8 +216,28 @@ impl Plugin for SpaceShipPlugin {
     }
 }
 
+fn buffer_player_input(
+    mut input: ResMut<PlayerInput>,
+    controls: Res<Controls>,
+    keys: Res<ButtonInput<KeyCode>>,
+) {
+    input.thrust |= keys.just_pressed(controls.thrust.unwrap());
+    input.back_thrust |= keys.just_pressed(controls.back_thrust.unwrap());
+    input.shoot_released |= keys.just_released(controls.shoot.unwrap());
+    input.missile_switch |= keys.just_pressed(controls.missile_switch.unwrap());
+    input.missile_shoot |= keys.just_pressed(controls.missile_shoot.unwrap());
+    input.missile_aim_released |= keys.just_released(controls.missile_aim.unwrap());
+}
+
+fn clear_player_input(mut input: ResMut<PlayerInput>) {
+    *input = PlayerInput::default();
+}
+
 fn spaceship_controls(
-    mut spaceship_query: Query<(Entity, &mut Inertia, &mut Direction), With<SpaceShip>>,
+    mut spaceship_query: Query<
+        (Entity, &mut Throttle, &RigidBody, &mut Direction),
+        With<SpaceShip>,
+    >,
     mut turret_query: Query<(Entity, &mut Turret), (With<SpaceShipTurret>, Without<SpaceShip>)>,
     mut ev_throttle_up: EventWriter<ThrottleUpEvent>,
     mut ev_turret_off: EventWriter<ShootTurretEventOff>,
@@ -197,26 +246,30 @@ fn spaceship_controls(
     missile_launcher: Res<SpaceShipMissileLauncher>,
     controls: Res<Controls>,
     keys: Res<ButtonInput<KeyCode>>,
+    input: Res<PlayerInput>,
     time: Res<Time>,
     entity: Res<Entities>,
     mut homing_target: ResMut<SpaceShipHomingTarget>,
 ) {
-    let (sp_ent, ref mut inertia, ref mut dir) = spaceship_query
-        .get_mut(entity.player.unwrap())
-        .expect("Can't get entity!");
-
-    if keys.just_pressed(controls.thrust.unwrap()) {
-        // if inertia.thrust == 0. {
+    // the player can be gone for the rest of the frame it died in
+    let Some(Ok((sp_ent, ref mut throttle, body, ref mut dir))) =
+        entity.player.map(|player| spaceship_query.get_mut(player))
+    else {
+        return;
+    };
+
+    if input.thrust {
+        // if throttle.0 == 0. {
         // }
-        if inertia.thrust != DEFAULT_THRUST_LIMIT {
-            inertia.thrust += 2.0;
-            ev_throttle_up.send(ThrottleUpEvent(sp_ent.clone(), inertia.thrust));
+        if throttle.0 != DEFAULT_THRUST_LIMIT {
+            throttle.0 += 2.0;
+            ev_throttle_up.send(ThrottleUpEvent(sp_ent.clone(), throttle.0));
         }
     }
-    if keys.just_pressed(controls.back_thrust.unwrap()) {
-        if inertia.thrust != -DEFAULT_THRUST_LIMIT {
-            inertia.thrust -= 2.0;
-            ev_throttle_up.send(ThrottleUpEvent(sp_ent.clone(), inertia.thrust));
+    if input.back_thrust {
+        if throttle.0 != -DEFAULT_THRUST_LIMIT {
+            throttle.0 -= 2.0;
+            ev_throttle_up.send(ThrottleUpEvent(sp_ent.clone(), throttle.0));
         }
     }
 
@@ -251,30 +304,6 @@ fn spaceship_controls(
             dir.1 = rotation.mul_vec3(dir.1);
         }
     }
-    if keys.pressed(controls.brake.unwrap()) {
-        let Vec3 {
-            mut x,
-            mut y,
-            mut z,
-        } = inertia.velocity.0;
-        x = if x.abs() < 0.1 {
-            0.0
-        } else {
-            x / (1.0 + 1. * time.delta_secs())
-        };
-        y = if y.abs() < 0.1 {
-            0.0
-        } else {
-            y / (1.0 + 1. * time.delta_secs())
-        };
-        z = if z.abs() < 0.1 {
-            0.0
-        } else {
-            z / (1.0 + 1. * time.delta_secs())
-        };
-        inertia.velocity.0 = Vec3 { x, y, z };
-    }
-
     if keys.pressed(controls.shoot.unwrap()) {
         for (ent, mut tur) in turret_query.iter_mut() {
             // error!("relesed");
@@ -284,18 +313,18 @@ fn spaceship_controls(
                 continue;
             }
             tur.0.shooting = true;
-            tur.0.bullet_inertial_velocity = inertia.velocity.0.clone();
+            tur.0.bullet_inertial_velocity = body.velocity;
             ev_turret_on.send(ShootTurretEventOn(ent.clone()));
         }
     }
-    if keys.just_released(controls.shoot.unwrap()) {
+    if input.shoot_released {
         for (ent, mut tur) in turret_query.iter_mut() {
             tur.0.shooting = false;
             ev_turret_off.send(ShootTurretEventOff(ent.clone()));
         }
     }
 
-    if keys.just_pressed(controls.missile_switch.unwrap()) {
+    if input.missile_switch {
         missile_equipped.0 = match missile_equipped.0 {
             MissileType::HomingMissile => {
                 // clear target if switched while aiming
@@ -314,12 +343,11 @@ fn missile_control(
     mut swarm_missile_queue: ResMut<SwarmMissileQueue>,
     mut s_timer: ResMut<SwarmMissileFireRate>,
     homing_target: ResMut<SpaceShipHomingTarget>,
-    query: Query<(Entity, &Inertia, &Transform), With<SpaceShip>>,
+    query: Query<(Entity, &RigidBody, &Transform), With<SpaceShip>>,
     missile_equipped: Res<MissileEquipped>,
     missile_launcher: Res<SpaceShipMissileLauncher>,
     s_query: Query<(Entity, &Transform), With<SwarmMissileTarget>>,
-    keys: Res<ButtonInput<KeyCode>>,
-    controls: Res<Controls>,
+    input: Res<PlayerInput>,
     time: Res<Time>,
 ) {
     if let Some(_) = &swarm_missile_queue.events {
@@ -329,9 +357,10 @@ fn missile_control(
             }
         }
     }
-    // will panic if more than two, will change later
-    let (sp_ent, inertia, trans) = query.single();
-    if keys.just_pressed(controls.missile_shoot.unwrap()) {
+    let Ok((sp_ent, body, trans)) = query.get_single() else {
+        return;
+    };
+    if input.missile_shoot {
         match missile_equipped.0 {
             MissileType::SwarmMissile => {
                 let mut missiles = Vec::<SwarmMissileShootEvent>::new();
@@ -366,7 +395,7 @@ fn missile_control(
                             stage: SwarmMissileStage::Stage1(dir),
                             source: sp_ent,
                             angluar_speed: 120.,
-                            initial_speed: inertia.velocity.0.length(),
+                            initial_speed: body.velocity.length(),
                             converge_point: (trans.translation
                                 + (angle * 3.0 / 30.) * trans.forward().as_vec3()),
                             speed: 0.,
@@ -392,12 +421,11 @@ fn missile_control(
                     source: sp_ent,
                     is_locked: homing_target.1.as_secs_f32() > 2.,
                     target: homing_target.0,
-                    initial_speed: inertia.velocity.0.length(),
+                    initial_speed: body.velocity.length(),
                     thrust: 30.,
                     timer: Duration::ZERO,
                     damage: 100.,
-                    velocity: inertia.velocity.0,
-                    drag: Vec3::ZERO,
+                    velocity: body.velocity,
                     angular_speed: 720.,
                 };
                 ev_homing_missile.send(HomingMissileShootEvent {
@@ -410,37 +438,58 @@ fn missile_control(
 }
 
 fn accelerate_spaceship(
-    mut spaceship_query: Query<(&mut Inertia, &mut Drag, &Direction), With<SpaceShip>>,
+    mut spaceship_query: Query<(&mut RigidBody, &Throttle, &Direction), With<SpaceShip>>,
+    controls: Res<Controls>,
+    keys: Res<ButtonInput<KeyCode>>,
     time: Res<Time>,
     entity: Res<Entities>,
 ) {
-    let (mut inertia, mut drag, dir) = spaceship_query
-        .get_mut(entity.player.unwrap())
-        .expect("Can't get entitiy!");
-
-    let acc = dir.0.normalize_or_zero() * inertia.thrust;
-    // info!("acc: {:?}", acc);
-    // info!("vel: {:?}", inertia.velocity.0);
+    let Some(Ok((mut body, throttle, dir))) =
+        entity.player.map(|player| spaceship_query.get_mut(player))
+    else {
+        return;
+    };
 
-    let Vec3 { x, y, z } = inertia.velocity.0.clone() + (acc + drag.0) * time.delta_secs();
+    body.add_acceleration(dir.0.normalize_or_zero() * throttle.0);
 
-    inertia.velocity.0 = Vec3 { x, y, z };
-
-    // inertia.angular_velocity =
-    //     inertia.angular_velocity - inertia.angular_velocity * 2. * time.delta_secs();
-
-    //* come up with a better drag function
-    drag.0 = -inertia.velocity.0.clone() * 2.;
+    if keys.pressed(controls.brake.unwrap()) {
+        let Vec3 {
+            mut x,
+            mut y,
+            mut z,
+        } = body.velocity;
+        x = if x.abs() < 0.1 {
+            0.0
+        } else {
+            x / (1.0 + 1. * time.delta_secs())
+        };
+        y = if y.abs() < 0.1 {
+            0.0
+        } else {
+            y / (1.0 + 1. * time.delta_secs())
+        };
+        z = if z.abs() < 0.1 {
+            0.0
+        } else {
+            z / (1.0 + 1. * time.delta_secs())
+        };
+        // the brake holds against gravity as well
+        body.accelerate_towards(Vec3 { x, y, z }, time.delta_secs(), f32::INFINITY);
+    }
 }
 
 fn aim_homing(
     controls: Res<Controls>,
     keys: Res<ButtonInput<KeyCode>>,
+    input: Res<PlayerInput>,
     missile_equipped: Res<MissileEquipped>,
     mut target: ResMut<SpaceShipHomingTarget>,
-    hm_query: Query<&GlobalTransform, With<HomingMissileLauncher>>,
+    hm_query: Query<(&GlobalTransform, &HomingMissileLauncher)>,
     launcher: Res<SpaceShipMissileLauncher>,
     ht_query: Query<(Entity, &Transform), With<HomingMissileTarget>>,
+    faction_query: Query<&Faction>,
+    faction_table: Res<FactionTable>,
+    oct_tree: Res<OctTree>,
     time: Res<Time>,
 ) {
     if keys.pressed(controls.missile_aim.unwrap()) {
@@ -451,7 +500,7 @@ fn aim_homing(
                     // if that target still exists
                     if let Ok((_, t_trans)) = ht_query.get(target.0.unwrap()) {
                         // if launcher exists (in case the player dies while aiming)
-                        if let Ok(l_trans) = hm_query.get(launcher.homing_launcher.unwrap()) {
+                        if let Ok((l_trans, _)) = hm_query.get(launcher.homing_launcher.unwrap()) {
                             let dir_vec = t_trans.translation - l_trans.translation();
                             let dist = dir_vec.length_squared();
                             let angle = l_trans.forward().dot(dir_vec.normalize_or_zero()).acos();
@@ -470,18 +519,28 @@ fn aim_homing(
                 }
                 if target.0.is_none() {
                     // search for new target
-                    let mut f_dist = 101.;
                     let mut f_ent = None;
-                    if let Ok(l_trans) = hm_query.get(launcher.homing_launcher.unwrap()) {
-                        for (ent, t_trans) in ht_query.iter() {
-                            let dir_vec = t_trans.translation - l_trans.translation();
-                            let dist = dir_vec.length_squared();
-                            let angle = l_trans.forward().dot(dir_vec.normalize_or_zero()).acos();
-                            if dist < 100. && angle < PI / 2. && dist < f_dist {
-                                f_dist = dist;
-                                f_ent = Some(ent);
-                            }
-                        }
+                    if let Ok((l_trans, homing_launcher)) =
+                        hm_query.get(launcher.homing_launcher.unwrap())
+                    {
+                        let faction = homing_launcher
+                            .source
+                            .and_then(|source| faction_query.get(source).ok());
+                        // closest target in view
+                        f_ent = oct_tree
+                            .get_in_cone(
+                                l_trans.translation(),
+                                l_trans.forward().as_vec3(),
+                                PI / 2.,
+                                10.,
+                            )
+                            .into_iter()
+                            .find(|(ent, _)| {
+                                ht_query.contains(*ent)
+                                    && faction_table
+                                        .is_hostile(faction, faction_query.get(*ent).ok())
+                            })
+                            .map(|(ent, _)| ent);
                     }
                     if f_ent.is_some() {
                         target.0 = f_ent;
@@ -491,7 +550,7 @@ fn aim_homing(
             MissileType::SwarmMissile => {}
         }
     }
-    if keys.just_released(controls.missile_aim.unwrap()) {
+    if input.missile_aim_released {
         target.reset();
     }
 }
@@ -512,27 +571,14 @@ fn draw_aim_lock(
     }
 }
 
-fn move_spaceship(
-    mut query: Query<(&mut Transform, &Inertia), With<SpaceShip>>,
-    entity: Res<Entities>,
-    time: Res<Time>,
-) {
-    let (mut trans, iner) = query
-        .get_mut(entity.player.unwrap())
-        .expect("Error getting entity player!");
-
-    trans.translation += iner.velocity.0 * time.delta_secs();
-    // info!("{}", iner.velocity.0.length());
-}
-
 fn spaceship_orientation(
     mut query: Query<(&mut Transform, &Direction), With<SpaceShip>>,
     entities: Res<Entities>,
     // time: Res<Time>,
 ) {
-    let (ref mut trans, dir) = query
-        .get_mut(entities.player.unwrap())
-        .expect("Cannot get player entity!");
+    let Some(Ok((ref mut trans, dir))) = entities.player.map(|player| query.get_mut(player)) else {
+        return;
+    };
 
     let curr_dir = trans.forward();
     let target_dir = dir.0.clone();
@@ -552,20 +598,25 @@ fn spaceship_orientation(
 }
 
 fn game_over(
-    query: Query<(Entity, &Health), With<SpaceShip>>,
-    mut commands: Commands,
+    query: Query<(), With<SpaceShip>>,
+    mut ev_destroyed: EventReader<EntityDestroyed>,
     mut in_game_state: ResMut<NextState<InGameStates>>,
+    mut entities: ResMut<Entities>,
 ) {
-    // will panic if more than one
-    let (ent, health) = query.single();
-    if health.0 <= 0.0 {
-        commands.entity(ent).despawn_recursive();
-        in_game_state.set(InGameStates::Over);
+    for ev in ev_destroyed.read() {
+        if query.contains(ev.entity) {
+            // despawned this tick, the state only changes next frame
+            if entities.player == Some(ev.entity) {
+                entities.player = None;
+            }
+            in_game_state.set(InGameStates::Over);
+        }
     }
 }
 
 fn clean_resources(mut commands: Commands) {
     commands.remove_resource::<MissileEquipped>();
+    commands.remove_resource::<PlayerInput>();
     commands.remove_resource::<SpaceShipHomingTarget>();
     commands.remove_resource::<SpaceShipMissileLauncher>();
     commands.remove_resource::<SwarmMissileFireRate>();
@@ -579,6 +630,7 @@ pub fn setup(
     mut entities: ResMut<Entities>,
 ) {
     commands.insert_resource(MissileEquipped(MissileType::HomingMissile));
+    commands.insert_resource(PlayerInput::default());
     commands.insert_resource(SpaceShipHomingTarget(None, Duration::ZERO));
     commands.insert_resource(SwarmMissileFireRate(Timer::new(
         Duration::from_millis(125),
@@ -608,12 +660,8 @@ pub fn setup(
                         model: SceneRoot(spaceship_scene),
                         health: Health(DEFAULT_HEALTH),
                         marker: SpaceShip,
-                        position: Position(DEFAULT_SPAWN.clone()),
-                        drag: Drag(DEFAULT_DRAG.clone()),
-                        inertia: Inertia {
-                            thrust: 0.,
-                            ..default()
-                        },
+                        throttle: Throttle(0.),
+                        body: RigidBody::new(DEFAULT_MASS).with_drag(DEFAULT_DRAG, 0.),
                         direction: Direction(
                             DEFAULT_DIRECTION.0.clone(),
                             DEFAULT_DIRECTION.1.clone(),
@@ -632,14 +680,7 @@ pub fn setup(
                     GameObjectMarker,
                     SwarmTarget,
                     BotTargetMarker,
-                    ColliderInfo {
-                        collider_type: ColliderType::Sphere,
-                        collider: Arc::new(RwLock::new(SphericalCollider {
-                            radius: 0.3,
-                            center: Vec3::ZERO,
-                        })),
-                        immune_to: None,
-                    },
+                    AutoCollider::sphere(CollisionLayers::player()),
                     ColliderMarker,
                     ExplosibleObjectMarker,
                     listener.clone(),
@@ -655,6 +696,7 @@ pub fn setup(
                                     speed: 10.,
                                     bullet_size: 0.0002,
                                     shooter: Some(parent.parent_entity()),
+                                    bullet_layers: CollisionLayers::player_projectile(),
                                     ..default()
                                 }),
                                 AudioPlayer(audio_assets.laser_turret.clone()),
@@ -679,6 +721,7 @@ pub fn setup(
                             speed: 10.,
                             bullet_size: 0.0002,
                             shooter: Some(parent.parent_entity()),
+                            bullet_layers: CollisionLayers::player_projectile(),
                             ..default()
                         }),
                         TurretMarker,
@@ -742,12 +785,17 @@ pub fn setup(
             swarm_launcher_right.unwrap(),
             homing_launcher.unwrap(),
         ]);
-        commands
-            .entity(entities.player.unwrap())
-            .insert(CollisionDamage {
+        commands.entity(entities.player.unwrap()).insert((
+            CollisionDamage {
                 damage: 10.,
+                kind: DamageType::Collision,
                 from: entities.player,
-            });
+                mode: DamageMode::Interval(Duration::from_millis(500)),
+            },
+            Restitution(0.5),
+            Shield::new(PLAYER_SHIELD, 10., 3.),
+            Faction::Player,
+        ));
         commands.insert_resource(launchers);
     } else {
         info!("Asset not loaded!")
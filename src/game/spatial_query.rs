@@ -0,0 +1,155 @@
+use super::collider::{
+    closest_between_segments, ColliderInfo, ColliderMarker, ColliderShape, SphericalCollider,
+};
+use super::oct_tree::OctTree;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SpatialHit {
+    pub entity: Entity,
+    pub distance: f32,
+    // points out of the hit collider, towards the caster
+    pub normal: Vec3,
+}
+
+// ray, shape cast and overlap queries against the colliders in the oct tree,
+// the filter is called for every candidate and can reject it by returning false
+#[derive(SystemParam)]
+pub struct SpatialQuery<'w, 's> {
+    oct_tree: Res<'w, OctTree>,
+    colliders: Query<'w, 's, (Entity, &'static ColliderInfo), With<ColliderMarker>>,
+}
+
+impl SpatialQuery<'_, '_> {
+    // first collider hit by the ray, points are too thin for a ray and only show up in shape casts
+    pub fn cast_ray(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+        filter: impl Fn(Entity, &ColliderInfo) -> bool,
+    ) -> Option<SpatialHit> {
+        self.cast_sphere(origin, 0., dir, max_dist, filter)
+    }
+
+    // first collider hit by a sphere of the given radius moving from origin along dir
+    pub fn cast_sphere(
+        &self,
+        origin: Vec3,
+        radius: f32,
+        dir: Vec3,
+        max_dist: f32,
+        filter: impl Fn(Entity, &ColliderInfo) -> bool,
+    ) -> Option<SpatialHit> {
+        let dir = dir.try_normalize()?;
+        let mut closest: Option<SpatialHit> = None;
+        for entity in self
+            .oct_tree
+            .get_along_segment(origin, origin + dir * max_dist, radius)
+        {
+            let Ok((entity, ci)) = self.colliders.get(entity) else {
+                continue;
+            };
+            if !filter(entity, ci) {
+                continue;
+            }
+            let Some((distance, normal)) = cast_against(&ci.shape, origin, dir, max_dist, radius)
+            else {
+                continue;
+            };
+            if closest.is_none_or(|hit| distance < hit.distance) {
+                closest = Some(SpatialHit {
+                    entity,
+                    distance,
+                    normal,
+                });
+            }
+        }
+        closest
+    }
+
+    // colliders touching the sphere sorted by distance, the normal points from the collider to the center
+    pub fn overlap_sphere(
+        &self,
+        center: Vec3,
+        radius: f32,
+        filter: impl Fn(Entity, &ColliderInfo) -> bool,
+    ) -> Vec<SpatialHit> {
+        let probe = ColliderShape::Sphere(SphericalCollider { radius, center });
+        let mut hits: Vec<SpatialHit> = self
+            .oct_tree
+            .get_local_objects(center, radius)
+            .into_iter()
+            .filter_map(|entity| {
+                let (entity, ci) = self.colliders.get(entity).ok()?;
+                if !filter(entity, ci) {
+                    return None;
+                }
+                let contact = probe.contact(&ci.shape)?;
+                Some(SpatialHit {
+                    entity,
+                    distance: (contact.point - center).length(),
+                    normal: contact.normal,
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+}
+
+// distance along the normalized dir at which a sphere of the given radius touches the shape
+fn cast_against(
+    shape: &ColliderShape,
+    origin: Vec3,
+    dir: Vec3,
+    max_dist: f32,
+    radius: f32,
+) -> Option<(f32, Vec3)> {
+    match shape {
+        ColliderShape::Box(b) => b.ray_hit(origin, dir, max_dist, radius),
+        // hit the sphere around the point of the axis closest to the ray
+        ColliderShape::Capsule(c) => {
+            let (a, b) = c.endpoints();
+            let (on_ray, on_axis) = closest_between_segments(origin, origin + dir * max_dist, a, b);
+            if (on_ray - on_axis).length() > c.radius + radius {
+                return None;
+            }
+            ray_hits_sphere(origin, dir, max_dist, on_axis, c.radius + radius)
+        }
+        _ => ray_hits_sphere(
+            origin,
+            dir,
+            max_dist,
+            shape.center(),
+            shape.radius() + radius,
+        ),
+    }
+}
+
+pub fn ray_hits_sphere(
+    origin: Vec3,
+    dir: Vec3,
+    max_dist: f32,
+    center: Vec3,
+    radius: f32,
+) -> Option<(f32, Vec3)> {
+    let oc = origin - center;
+    // already inside, hit right away
+    if oc.length_squared() <= radius * radius {
+        return Some((0., -dir));
+    }
+    let b = oc.dot(dir);
+    let c = oc.length_squared() - radius * radius;
+    let discriminant = b * b - c;
+    if b > 0. || discriminant < 0. {
+        return None;
+    }
+    let t = -b - discriminant.sqrt();
+    if t > max_dist {
+        return None;
+    }
+    let normal = (origin + dir * t - center).normalize_or(-dir);
+    Some((t, normal))
+}
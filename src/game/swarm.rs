@@ -1,15 +1,30 @@
-use super::explosion::{ExplosibleObjectMarker, *};
+use super::auto_collider::AutoCollider;
+use super::damage::{DamageType, EntityDestroyed};
+use super::debug::{ObstacleInfo, ObstacleMarker};
+use super::explosion::ExplosibleObjectMarker;
+use super::faction::{Faction, FactionTable};
 use super::missile::SwarmMissileTarget;
+use super::movement::RigidBody;
+use super::oct_tree::OctTree;
+use super::spaceship::Health;
+use super::swarm_behavior::SwarmBehavior;
+use super::swarm_formation::*;
+use super::swarm_membership::*;
 use super::{collider::*, GameObjectMarker};
-use crate::asset_loader::{AudioAssets, SceneAssets};
+use crate::asset_loader::SceneAssets;
 use crate::sets::*;
 use crate::states::*;
 use bevy::prelude::*;
 use rand::Rng;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
+const SWARM_BOT_HEALTH: f32 = 40.;
+const SWARM_BOT_MASS: f32 = 1.;
+const SWARM_BOT_DRAG: f32 = 0.5;
+// roughly the largest flocking radius, so most lookups touch few cells
+const SWARM_GRID_CELL_SIZE: f32 = 2.;
+
 #[derive(Component)]
 pub struct SwarmTarget;
 
@@ -20,6 +35,7 @@ pub struct SwarmPointMarker;
 pub struct SwarmBotMarker;
 
 #[derive(Component)]
+#[require(SwarmBehavior, Formation)]
 pub struct SwarmPoint {
     origin: Vec3,
     burst_count: u32,
@@ -30,97 +46,72 @@ pub struct SwarmPoint {
     radius: f32,
 }
 
-#[derive(Clone)]
-pub struct SwarmData {
-    leader: Option<Entity>,
-    followers: u32,
-    followers_limit: u32,
-    state: SwarmBotState,
-    last_state: SwarmBotState,
-    swarm_id: u32,
+// uniform grid over the swarm bots, rebuilt at the start of every tick so the
+// flocking systems only look at bots within their interaction radius
+#[derive(Resource, Default)]
+pub struct SwarmGrid {
+    cells: HashMap<IVec3, Vec<(Entity, Vec3)>>,
+    bots: HashMap<Entity, SwarmGridBot>,
 }
 
-impl Default for SwarmData {
-    fn default() -> Self {
-        Self {
-            leader: None,
-            followers: 0,
-            followers_limit: 10,
-            state: SwarmBotState::Solo,
-            last_state: SwarmBotState::Solo,
-            swarm_id: 0,
-        }
-    }
+// a bot as it was at the time of the rebuild
+#[derive(Clone, Copy)]
+pub struct SwarmGridBot {
+    pub position: Vec3,
+    pub heading: Dir3,
+    pub velocity: Vec3,
 }
 
-#[derive(Clone)]
-pub struct SwarmIdPool {
-    front: i32,
-    back: i32,
-    limit: u32,
-    id_pool: Vec<u32>,
-    subs: Vec<u32>,
-}
+impl SwarmGrid {
+    fn cell(point: Vec3) -> IVec3 {
+        (point / SWARM_GRID_CELL_SIZE).floor().as_ivec3()
+    }
 
-impl Default for SwarmIdPool {
-    fn default() -> Self {
-        let limit: u32 = 20;
-        let mut id_pool = Vec::new();
-        let mut subs = Vec::new();
-        for i in 0..limit {
-            id_pool.push(i + 1);
-            subs.push(0);
-        }
-        Self {
-            front: 0,
-            back: limit as i32 - 1,
-            limit,
-            id_pool,
-            subs,
-        }
+    fn clear(&mut self) {
+        // keep the buckets used last tick around to avoid reallocating them
+        self.cells.retain(|_, bucket| {
+            let used = !bucket.is_empty();
+            bucket.clear();
+            used
+        });
+        self.bots.clear();
     }
-}
 
-impl SwarmIdPool {
-    fn get_id(&mut self) -> u32 {
-        if self.front != -1 {
-            let x = self.id_pool[self.front as usize];
-            self.unit_inc_subs(x as usize);
-            if self.front == self.back {
-                self.front = -1;
-                self.back = -1;
-            } else {
-                self.front = (self.front + 1) % self.limit as i32;
-            }
-            x
-        } else {
-            0
-        }
+    fn insert(&mut self, entity: Entity, bot: SwarmGridBot) {
+        self.cells
+            .entry(Self::cell(bot.position))
+            .or_default()
+            .push((entity, bot.position));
+        self.bots.insert(entity, bot);
     }
 
-    fn free_id(&mut self, id: u32) {
-        self.back = (self.back + 1) % self.limit as i32;
-        self.id_pool[self.back as usize] = id;
-        // self.unit_dec_subs(id as usize);
-        if self.front == -1 {
-            self.front = self.back;
-        }
+    pub fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.bots.keys().copied()
     }
-    fn unit_inc_subs(&mut self, x: usize) {
-        self.subs[x - 1] += 1;
+
+    pub fn get(&self, entity: Entity) -> Option<SwarmGridBot> {
+        self.bots.get(&entity).copied()
     }
-    fn unit_dec_subs(&mut self, x: usize) {
-        error!("subs {}", self.subs[x - 1]);
-        self.subs[x - 1] -= 1;
-        if self.subs[x - 1] == 0 {
-            self.free_id(x as u32);
-        }
+
+    // bots within radius of the point, the bot at the point included
+    pub fn neighbours(
+        &self,
+        point: Vec3,
+        radius: f32,
+    ) -> impl Iterator<Item = (Entity, Vec3)> + '_ {
+        let min = Self::cell(point - Vec3::splat(radius));
+        let max = Self::cell(point + Vec3::splat(radius));
+        (min.x..=max.x)
+            .flat_map(move |x| {
+                (min.y..=max.y).flat_map(move |y| (min.z..=max.z).map(move |z| IVec3::new(x, y, z)))
+            })
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .filter(move |(_, p)| p.distance_squared(point) <= radius * radius)
     }
 }
 
-#[derive(Resource, Clone)]
-pub struct SwarmTracker(HashMap<Entity, SwarmData>, SwarmIdPool);
-
 #[derive(Clone, PartialEq)]
 pub enum SwarmBotState {
     Swarming,
@@ -136,35 +127,32 @@ pub enum TargetVicinity {
     Near,
 }
 #[derive(Component, Clone)]
-#[require(SceneRoot)]
+#[require(
+    SceneRoot,
+    SwarmMember,
+    SwarmLeader,
+    SwarmBehavior,
+    Formation,
+    FormationSlot
+)]
 pub struct SwarmBot {
     swarm_point: Option<Entity>,
     dir: Dir3,
     target_dir: Dir3,
     target_vicinity: TargetVicinity,
     avoid_dir_vector: Dir3,
-    velocity: Vec3,
     thrust: f32,
     thrust_limit: f32,
     angular_velocity: f32,
-    drag: Vec3,
-    health: f32,
     in_swarm: bool,
     target_distance: f32,
-    swarm_data: SwarmData,
-    state: SwarmBotState,
-    swarm_up_distance: f32,
-    repel_thrust: f32,
-    swarm_spacing_min: f32,
-    swarm_spacing_max: f32,
-    is_alive: bool,
+    pub state: SwarmBotState,
 }
 
 impl Default for SwarmBot {
     fn default() -> Self {
         Self {
             swarm_point: None,
-            health: 40.,
             dir: Dir3::Y,
             target_dir: Dir3::Y,
             target_vicinity: TargetVicinity::Around,
@@ -172,17 +160,9 @@ impl Default for SwarmBot {
             thrust: 1.,
             thrust_limit: 10.,
             angular_velocity: 100.,
-            drag: Vec3::ZERO,
-            velocity: Vec3::ZERO,
             in_swarm: false,
             target_distance: 9999.,
-            swarm_data: SwarmData::default(),
             state: SwarmBotState::Solo,
-            swarm_up_distance: 2.,
-            repel_thrust: 0.2,
-            swarm_spacing_min: 0.1,
-            swarm_spacing_max: 0.5,
-            is_alive: true,
         }
     }
 }
@@ -215,6 +195,13 @@ impl Default for SwarmPoint {
 }
 
 impl SwarmPoint {
+    pub fn new(origin: Vec3) -> Self {
+        Self {
+            origin,
+            ..default()
+        }
+    }
+
     pub fn xyz(&self) -> Vec3 {
         self.origin.clone()
     }
@@ -223,42 +210,106 @@ impl SwarmPoint {
 pub struct SwarmPlugin;
 impl Plugin for SwarmPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(OnEnter(InGameStates::Setup), setup)
-            .add_systems(Update, release_bots.in_set(UpdateSet::InGame))
+        app.init_resource::<SwarmGrid>()
+            .init_resource::<SwarmSuccession>()
+            .add_systems(OnEnter(InGameStates::Setup), setup)
+            .add_systems(FixedUpdate, release_bots.in_set(UpdateSet::InGame))
             .add_systems(
-                Update,
+                FixedUpdate,
                 (
+                    rebuild_swarm_grid,
+                    elect_leaders,
+                    brace_for_missiles,
+                    assign_formation_slots,
                     detect_target,
                     thrust_control,
                     swarm_up,
+                    split_swarms,
+                    merge_swarms,
                     coerce,
                     avoidance,
+                    avoid_obstacles,
                     steer,
                     accelerate,
-                    move_bots,
-                    collision_response,
-                    despawn_swarm_bots,
                 )
                     .chain()
                     .in_set(UpdateSet::InGame),
             )
+            .add_systems(
+                FixedUpdate,
+                despawn_swarm_bots.in_set(DespawnSet::InGame(InGameSet::Swarm)),
+            )
             .add_systems(OnExit(GameState::Game), clear_resources)
             .add_systems(OnEnter(InGameStates::Over), clear_resources);
+        #[cfg(debug_assertions)]
+        app.add_systems(
+            FixedUpdate,
+            verify_swarms.after(accelerate).in_set(UpdateSet::InGame),
+        );
     }
 }
 
-fn clear_resources(mut commands: Commands) {
-    commands.remove_resource::<SwarmTracker>();
+fn clear_resources(
+    mut commands: Commands,
+    mut grid: ResMut<SwarmGrid>,
+    mut succession: ResMut<SwarmSuccession>,
+) {
+    commands.remove_resource::<SwarmIdPool>();
+    grid.clear();
+    succession.clear();
+}
+
+pub(super) fn rebuild_swarm_grid(
+    query_bots: Query<(Entity, &Transform, &RigidBody), With<SwarmBotMarker>>,
+    mut grid: ResMut<SwarmGrid>,
+) {
+    grid.clear();
+    for (entity, trans, body) in query_bots.iter() {
+        grid.insert(
+            entity,
+            SwarmGridBot {
+                position: trans.translation,
+                heading: trans.forward(),
+                velocity: body.velocity,
+            },
+        );
+    }
 }
 
 pub fn setup(mut commands: Commands, scene_assets: Res<SceneAssets>) {
-    commands.insert_resource(SwarmTracker(HashMap::new(), SwarmIdPool::default()));
-    let swarm = SwarmPoint::default();
-    let origin = swarm.xyz();
-    let transform =
-        Transform::from_xyz(origin.x, origin.y, origin.z).with_scale(Vec3::new(0.5, 0.5, 0.5));
-    let scene = SceneRoot(scene_assets.swarm_point.clone());
-    commands.spawn((swarm, transform, scene, SwarmPointMarker, GameObjectMarker));
+    commands.insert_resource(SwarmIdPool::default());
+    let swarm_points = [
+        (
+            SwarmPoint::default(),
+            SwarmBehavior::default(),
+            Formation::default(),
+        ),
+        (
+            SwarmPoint::new(Vec3::new(-8., 2., -8.)),
+            SwarmBehavior::hornet(),
+            Formation::new(FormationShape::V, 0.4),
+        ),
+        (
+            SwarmPoint::new(Vec3::new(8., 2., -8.)),
+            SwarmBehavior::cloud(),
+            Formation::new(FormationShape::Ring, 1.2).with_threat(FormationShape::Sphere, 0.8),
+        ),
+    ];
+    for (swarm, behavior, formation) in swarm_points {
+        let origin = swarm.xyz();
+        let transform =
+            Transform::from_xyz(origin.x, origin.y, origin.z).with_scale(Vec3::new(0.5, 0.5, 0.5));
+        let scene = SceneRoot(scene_assets.swarm_point.clone());
+        commands.spawn((
+            swarm,
+            behavior,
+            formation,
+            transform,
+            scene,
+            SwarmPointMarker,
+            GameObjectMarker,
+        ));
+    }
     // commands.spawn((
     //     SceneRoot(scene_assets.map_marker.clone()),
     //     Transform::from_xyz(-4., 4., -6.).with_scale(Vec3::new(0.05, 0.05, 0.05)),
@@ -275,19 +326,27 @@ pub fn setup(mut commands: Commands, scene_assets: Res<SceneAssets>) {
 
 fn release_bots(
     mut commands: Commands,
-    mut query_swarm_point: Query<(Entity, &Transform, &mut SwarmPoint), With<SwarmPointMarker>>,
+    mut query_swarm_point: Query<
+        (
+            Entity,
+            &Transform,
+            &mut SwarmPoint,
+            &SwarmBehavior,
+            &Formation,
+        ),
+        With<SwarmPointMarker>,
+    >,
     scene_assets: Res<SceneAssets>,
     mut meshes: ResMut<Assets<Mesh>>,
     time: Res<Time>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut swarm_tracker: ResMut<SwarmTracker>,
 ) {
     let mat_handle = materials.add(StandardMaterial {
         emissive: LinearRgba::rgb(13.99, 5.32, 10.0),
         ..default()
     });
     let time_delta = time.delta();
-    for (sp_ent, trans, mut swarm_point) in query_swarm_point.iter_mut() {
+    for (sp_ent, trans, mut swarm_point, behavior, formation) in query_swarm_point.iter_mut() {
         swarm_point.last_burst += time_delta;
         if swarm_point.last_burst < swarm_point.cooldown {
             continue;
@@ -312,8 +371,6 @@ fn release_bots(
 
             let bot = SwarmBot {
                 dir: Dir3::new(dir).unwrap_or(Dir3::Y),
-                velocity: dir * 0.0,
-                drag: Vec3::ZERO,
                 swarm_point: Some(sp_ent),
                 ..default()
             };
@@ -324,45 +381,32 @@ fn release_bots(
                 .with_scale(Vec3::new(0.1, 0.1, 0.1))
                 .looking_to(bot.dir.clone(), Dir3::Y);
             let scene = SceneRoot(scene_assets.bot_spaceship.clone());
-            let entity = commands
-                .spawn((
-                    bot,
-                    transform,
-                    scene,
-                    MeshMaterial3d(mat_handle.clone()),
-                    SwarmBotMarker,
-                    ExplosibleObjectMarker,
-                    ColliderMarker,
-                    CollisionDamage {
-                        damage: 10.,
-                        from: None,
-                    },
-                    ColliderInfo {
-                        collider_type: ColliderType::Sphere,
-                        collider: Arc::new(RwLock::new(SphericalCollider {
-                            radius: 0.05,
-                            center: Vec3::ZERO,
-                        })),
-                        immune_to: None,
-                    },
-                    SwarmMissileTarget,
-                    GameObjectMarker,
-                ))
-                .id();
-            swarm_tracker.0.insert(entity, SwarmData::default());
+            commands.spawn((
+                bot,
+                (*behavior, *formation),
+                RigidBody::new(SWARM_BOT_MASS).with_drag(SWARM_BOT_DRAG, 0.),
+                transform,
+                scene,
+                MeshMaterial3d(mat_handle.clone()),
+                SwarmBotMarker,
+                ExplosibleObjectMarker,
+                ColliderMarker,
+                CollisionDamage {
+                    damage: 10.,
+                    kind: DamageType::Collision,
+                    from: None,
+                    mode: DamageMode::Interval(Duration::from_millis(500)),
+                },
+                AutoCollider::sphere(CollisionLayers::enemy()),
+                SwarmMissileTarget,
+                GameObjectMarker,
+                Health(SWARM_BOT_HEALTH),
+                Faction::Swarm,
+            ));
         }
     }
 }
 
-fn move_bots(
-    mut query_bots: Query<(&mut Transform, &SwarmBot), With<SwarmBotMarker>>,
-    time: Res<Time>,
-) {
-    for (mut trans, swarm_bot) in query_bots.iter_mut() {
-        trans.translation += swarm_bot.velocity * time.delta_secs();
-    }
-}
-
 fn steer(
     mut query_bots: Query<(&mut Transform, &mut SwarmBot), With<SwarmBotMarker>>,
     time: Res<Time>,
@@ -383,14 +427,23 @@ fn steer(
     }
 }
 
-fn accelerate(mut query_bots: Query<&mut SwarmBot, With<SwarmBotMarker>>, time: Res<Time>) {
-    for mut swarm_bot in query_bots.iter_mut() {
-        swarm_bot.velocity = {
-            swarm_bot.velocity
-                + (swarm_bot.drag.clone() + (swarm_bot.thrust * swarm_bot.dir.as_vec3()))
-                    * time.delta_secs()
-        };
-        swarm_bot.drag = -0.5 * swarm_bot.velocity;
+fn accelerate(
+    mut query_bots: Query<
+        (
+            Entity,
+            &SwarmBot,
+            &SwarmMember,
+            &SwarmBehavior,
+            &mut RigidBody,
+        ),
+        With<SwarmBotMarker>,
+    >,
+) {
+    for (entity, swarm_bot, member, behavior, mut body) in query_bots.iter_mut() {
+        // only bots chasing the target themselves seek, followers keep up with the leader
+        let seeking = member.leader.is_none() || member.leader == Some(entity);
+        let weight = if seeking { behavior.seek_weight } else { 1. };
+        body.add_acceleration(weight * swarm_bot.thrust * swarm_bot.dir.as_vec3());
     }
 }
 
@@ -422,25 +475,38 @@ fn thrust_control(mut query_bots: Query<&mut SwarmBot, With<SwarmBotMarker>>, ti
 }
 
 fn detect_target(
-    mut query_bots: Query<(Entity, &Transform, &mut SwarmBot), With<SwarmBotMarker>>,
+    mut query_bots: Query<
+        (
+            Entity,
+            &Transform,
+            &mut SwarmBot,
+            &SwarmMember,
+            Option<&Faction>,
+        ),
+        With<SwarmBotMarker>,
+    >,
     query_target: Query<&Transform, (With<SwarmTarget>, Without<SwarmBotMarker>)>,
-    swarm_tracker: Res<SwarmTracker>,
+    faction_query: Query<&Faction>,
+    faction_table: Res<FactionTable>,
+    oct_tree: Res<OctTree>,
 ) {
-    for (entity, trans_bot, mut bot) in query_bots.iter_mut() {
+    for (entity, trans_bot, mut bot, member, faction) in query_bots.iter_mut() {
         let mut target: Vec3 = Vec3::ZERO;
         let mut dist: f32 = 9999.;
-        let sd = swarm_tracker.0.get(&entity).unwrap();
-        if sd.state == SwarmBotState::Solo
-            || (sd.state == SwarmBotState::InSwarm && sd.leader.unwrap() == entity)
+        if bot.state == SwarmBotState::Solo
+            || (bot.state == SwarmBotState::InSwarm && member.leader == Some(entity))
         {
-            query_target.iter().for_each(|trans_target| {
-                let target_dist = (trans_target.translation - trans_bot.translation).length();
-                if dist > target_dist {
+            let nearest = oct_tree.get_k_nearest(trans_bot.translation, 1, |e| {
+                query_target.contains(e)
+                    && faction_table.is_hostile(faction, faction_query.get(e).ok())
+            });
+            if let Some((t_ent, _)) = nearest.first() {
+                if let Ok(trans_target) = query_target.get(*t_ent) {
                     target = trans_target.translation;
-                    dist = target_dist;
+                    dist = (trans_target.translation - trans_bot.translation).length();
                     // info!("target detected");
                 }
-            });
+            }
 
             let dir = (target - trans_bot.translation).normalize_or_zero();
             bot.target_dir = Dir3::new(dir).unwrap_or(Dir3::Y);
@@ -451,311 +517,241 @@ fn detect_target(
 }
 
 fn avoidance(
-    mut query_bots: Query<(Entity, &Transform, &mut SwarmBot), With<SwarmBotMarker>>,
-    time: Res<Time>,
+    mut query_bots: Query<
+        (Entity, &Transform, &SwarmBehavior, &mut RigidBody),
+        With<SwarmBotMarker>,
+    >,
+    grid: Res<SwarmGrid>,
 ) {
-    let mut query = query_bots.iter_combinations_mut();
-    // let mut_bots: Vec<_> = query_bots.iter_mut().collect();
-    while let Some([(_, t1, mut b1), (_, t2, _)]) = query.fetch_next() {
-        let diff = (t2.translation - t1.translation).length();
-        if diff < b1.swarm_spacing_min && diff > 0. {
-            let dir = (t2.translation - t1.translation).normalize();
-            b1.velocity = b1.velocity - (b1.velocity.dot(dir) * dir);
-        } else if diff < b1.swarm_spacing_max && diff > 0. {
-            let dir = (t1.translation - t2.translation).normalize();
-            b1.velocity = b1.velocity + (b1.repel_thrust * time.delta_secs() * dir.normalize());
+    for (e1, t1, behavior, mut rb1) in query_bots.iter_mut() {
+        for (e2, p2) in grid.neighbours(t1.translation, behavior.separation_radius) {
+            if e1 == e2 {
+                continue;
+            }
+            let diff = (p2 - t1.translation).length();
+            if diff < behavior.separation_min && diff > 0. {
+                // cancel the velocity towards the other bot
+                let dir = (p2 - t1.translation).normalize();
+                let impulse = -rb1.velocity.dot(dir) * dir * rb1.mass;
+                rb1.apply_impulse(impulse);
+            } else if diff < behavior.separation_radius && diff > 0. {
+                let dir = (t1.translation - p2).normalize();
+                rb1.add_acceleration(behavior.separation_weight * dir);
+            }
         }
     }
 }
 
-fn collision_response(
-    mut query: Query<
-        (
-            Entity,
-            &Transform,
-            &ColliderInfo,
-            &mut SwarmBot,
-            Option<&ExplosibleObjectMarker>,
-        ),
-        With<SwarmBotMarker>,
-    >,
-    audio_asset: Res<AudioAssets>,
-    mut ev_reader: EventReader<CollisionEvents>,
-    mut ev_explode: EventWriter<ExplosionEvent>,
+// pushes bots away from the surface of nearby obstacles
+fn avoid_obstacles(
+    mut query_bots: Query<(&Transform, &SwarmBehavior, &mut RigidBody), With<SwarmBotMarker>>,
+    query_obstacles: Query<(&GlobalTransform, &ObstacleInfo), With<ObstacleMarker>>,
+    oct_tree: Res<OctTree>,
 ) {
-    // let health = query.single();
-    for msg in ev_reader.read() {
-        match msg {
-            CollisionEvents::TakeDamage(e, d, _) => {
-                if let Ok((ent, trans, c_info, mut s_bot, ex_object)) = query.get_mut(e.clone()) {
-                    if d.from.is_some_and(|e| e != ent) || d.from.is_none() {
-                        if !s_bot.is_alive {
-                            continue;
-                        }
-                        s_bot.health -= d.damage;
-                        if s_bot.health <= 0. {
-                            s_bot.is_alive = false;
-                            if ex_object.is_some() {
-                                info!("explosion sent");
-                                ev_explode.send(ExplosionEvent {
-                                    transform: trans.clone(),
-                                    explosion: Explosion {
-                                        half_extent: 0.15,
-                                        ..default()
-                                    },
-                                    sound: Some(audio_asset.small_explosion.clone()),
-                                });
-                            }
-                            // s_bot.health = 20.
-                            // commands.entity(ent).despawn_recursive();
-                            // todo!();
-                        }
-                        // info!("Health {}", s_bot.health);
-                    }
-                }
+    for (trans, behavior, mut body) in query_bots.iter_mut() {
+        if behavior.avoidance_radius <= 0. {
+            continue;
+        }
+        let nearby = oct_tree.get_local_objects(trans.translation, behavior.avoidance_radius);
+        for (gt, obstacle) in nearby.iter().filter_map(|e| query_obstacles.get(*e).ok()) {
+            let away = trans.translation - gt.translation();
+            let gap = away.length() - obstacle.radius;
+            if gap < behavior.avoidance_radius {
+                let closeness = 1. - gap.max(0.) / behavior.avoidance_radius;
+                body.add_acceleration(
+                    behavior.avoidance_weight * closeness * away.normalize_or_zero(),
+                );
             }
         }
     }
 }
 
+// swarm membership is cleaned up by the SwarmMember hook when the bot is despawned
 pub fn despawn_swarm_bots(
-    query: Query<(Entity, &SwarmBot), With<SwarmBotMarker>>,
+    query: Query<&SwarmBot, With<SwarmBotMarker>>,
     mut query_swarm_point: Query<&mut SwarmPoint, With<SwarmPointMarker>>,
-    mut commands: Commands,
-    mut swarm_tracker: ResMut<SwarmTracker>,
+    mut ev_destroyed: EventReader<EntityDestroyed>,
 ) {
-    // let swarm_map = &mut swarm_tracker.0;
-    // let id_pool = &swarm_tracker.1;
-    for (ent, s_bot) in query.iter() {
-        // info!("despawn {}", s_bot.health);
-        if !s_bot.is_alive {
-            // error!("despawning");
-            if s_bot.swarm_point.is_some() {
-                if let Ok(mut sp) = query_swarm_point.get_mut(s_bot.swarm_point.unwrap()) {
-                    sp.live_bots -= 1;
-                }
-            }
-            commands.entity(ent).despawn_recursive();
-            if let Some(sd) = swarm_tracker.clone().0.get(&ent) {
-                // check if it's in a swarm or not
-                // if sd.swarm_id
-                if let Some(leader) = &sd.leader {
-                    error!("sward id {}", sd.swarm_id);
-                    swarm_tracker.1.unit_dec_subs(sd.swarm_id as usize);
-                    if *leader != ent {
-                        if let Some(l_sd) = swarm_tracker.0.get_mut(leader) {
-                            l_sd.followers -= 1;
-                        }
-                    }
-                }
+    for EntityDestroyed { entity: ent, .. } in ev_destroyed.read() {
+        let Ok(s_bot) = query.get(*ent) else {
+            continue;
+        };
+        if s_bot.swarm_point.is_some() {
+            if let Ok(mut sp) = query_swarm_point.get_mut(s_bot.swarm_point.unwrap()) {
+                sp.live_bots -= 1;
             }
-            // delete from swarm tracker
-            swarm_tracker.0.remove(&ent);
         }
     }
 }
 
+// cohesion towards the formation slot and alignment with the leader
 fn coerce(
-    mut query: Query<(Entity, &Transform, &mut SwarmBot), With<SwarmBotMarker>>,
-    swarm_tracker: Res<SwarmTracker>,
-    time: Res<Time>,
+    mut query: Query<
+        (
+            Entity,
+            &Transform,
+            &SwarmMember,
+            &FormationSlot,
+            &SwarmBehavior,
+            &mut RigidBody,
+        ),
+        With<SwarmBotMarker>,
+    >,
+    grid: Res<SwarmGrid>,
 ) {
-    let mut bot_query = query.iter_combinations_mut();
-    while let Some([(e1, t1, mut sb1), (e2, t2, sb2)]) = bot_query.fetch_next() {
-        if let Some(leader) = swarm_tracker.0.get(&e1).unwrap().leader {
-            if leader == e2 {
-                let dir = t2.translation - t1.translation;
-                let diff = dir.length();
-                if diff > sb1.swarm_spacing_max {
-                    sb1.velocity = sb1.velocity + (0.3 * dir.normalize() * time.delta_secs());
-                }
+    for (e1, t1, member, slot, behavior, mut rb1) in query.iter_mut() {
+        let Some(leader) = member.leader else {
+            continue;
+        };
+        if leader == e1 {
+            continue;
+        }
+        if let Some(l) = grid.get(leader) {
+            let dir = slot.target(leader).unwrap_or(l.position) - t1.translation;
+            if dir.length() > behavior.separation_radius {
+                rb1.add_acceleration(behavior.cohesion_weight * dir.normalize());
             }
+            let velocity_diff = l.velocity - rb1.velocity;
+            rb1.add_acceleration(behavior.alignment_weight * velocity_diff);
         }
     }
 }
 
 fn swarm_up(
-    mut query_bots: Query<(Entity, &Transform, &mut SwarmBot), With<SwarmBotMarker>>,
-    mut swarm_tracker: ResMut<SwarmTracker>,
+    mut query_bots: Query<
+        (
+            &Transform,
+            &mut SwarmBot,
+            &mut SwarmMember,
+            &mut SwarmLeader,
+            &SwarmBehavior,
+            &FormationSlot,
+        ),
+        With<SwarmBotMarker>,
+    >,
+    body_query: Query<&RigidBody, With<SwarmBotMarker>>,
+    mut id_pool: ResMut<SwarmIdPool>,
+    grid: Res<SwarmGrid>,
 ) {
-    let bots: Vec<_> = query_bots
-        .iter()
-        .map(|(e, t, b)| (e.clone(), t.clone(), b.clone()))
-        .collect();
-
-    let bots_map: HashMap<Entity, _> = bots
-        .clone()
-        .iter()
-        .map(|(e, t, b)| (e.clone(), (t.clone(), b.clone())))
-        .collect();
-
-    for (e1, t1, mut b1) in query_bots.iter_mut() {
-        if let Some(swarm_data) = swarm_tracker.0.clone().get(&e1) {
-            match swarm_data.state {
-                SwarmBotState::Solo => {
-                    for (e2, t2, b2) in bots.iter() {
-                        if e1 == *e2 {
-                            continue;
-                        }
-
-                        // verify if this is necessary
-                        if swarm_tracker.0[&e2].leader == Some(e1) {
-                            continue;
-                        }
-
-                        let angle = b1.velocity.normalize().dot(b2.velocity.normalize()).acos();
-                        if ((t1.translation - t2.translation).length() <= b1.swarm_up_distance)
-                            && (angle <= std::f32::consts::PI / 3. && angle >= 0.)
-                            && (swarm_tracker.0.get(&e2).unwrap().followers
-                                < swarm_tracker.0.get(&e2).unwrap().followers_limit)
-                        {
-                            //swarm up only when coming from behind
-                            //right now swarming up the first bot it sees, not with the closest one
-
-                            //first swarm up with the first one later move to its leader if it has space like a balanced tree
-                            // b1.swarm_data.leader = Some(e2.clone());
-                            // let t_swarm_data: SwarmData;
-
-                            //checking if the swarm already exists and assigning swarm id if not
-                            let mut swarm_id: u32 = 0;
-                            if let Some(sd) = swarm_tracker.0.get(&e2) {
-                                if sd.swarm_id != 0 {
-                                    swarm_id = sd.swarm_id;
-                                } else {
-                                    // what if this fails later but the get id increased the sub count
-                                    swarm_id = swarm_tracker.1.get_id();
-                                    if swarm_id == 0 {
-                                        continue;
-                                    }
-                                }
-                            } else {
-                                error!("entity should have been present!!");
-                            }
-                            swarm_tracker.0.entry(e2.clone()).and_modify(|d| {
-                                d.followers += 1;
-                                if d.state == SwarmBotState::Solo {
-                                    // d.state = SwarmBotState::InSwarm;
-                                    d.swarm_id = swarm_id;
-                                    d.leader = Some(e2.clone());
-                                }
-                                d.state = SwarmBotState::InSwarm;
-                            });
-
-                            // info!("{} swarming with {}", e1.to_bits(), e2.to_bits());
-                            swarm_tracker.0.entry(e1.clone()).and_modify(|d| {
-                                d.state = SwarmBotState::Swarming;
-                                d.leader = Some(e2.clone());
-                                d.swarm_id = swarm_id;
-                            });
-                            // update the subs count
-                            swarm_tracker.1.unit_inc_subs(swarm_id as usize);
-                            // b1 aims for the leader while swarming
-                            b1.target_dir =
-                                Dir3::new((t2.translation - t1.translation).normalize_or(Vec3::Y))
-                                    .unwrap();
-                            break;
-                        }
+    for e1 in grid.entities() {
+        let Ok((t1, b1, m1, _, behavior, slot)) = query_bots.get(e1) else {
+            error!("Entity should be present!!");
+            continue;
+        };
+        let p1 = t1.translation;
+        let leader = m1.leader;
+        let behavior = *behavior;
+        let slot = *slot;
+        match b1.state {
+            SwarmBotState::Solo => {
+                for (e2, p2) in grid.neighbours(p1, behavior.cohesion_radius) {
+                    if e1 == e2 {
+                        continue;
                     }
-                }
-                SwarmBotState::Swarming => {
-                    let leader = swarm_tracker.0.get(&e1).unwrap().leader.unwrap();
-                    let leader_transform = bots
-                        .iter()
-                        .find(|(e, _, _)| *e == leader)
-                        .map(|(e2, t, b2)| (e2.clone(), t.clone(), b2.clone()));
-                    if let Some((_, l_trans, b2)) = leader_transform {
-                        // verify if this is required
-                        if leader == e1 {
-                            continue;
-                        }
-                        // Perform operations with leader_transform
-                        if (l_trans.translation - t1.translation).length() <= b1.swarm_spacing_max {
-                            swarm_tracker.0.entry(e1.clone()).and_modify(|d| {
-                                d.state = SwarmBotState::InSwarm;
-                            });
-
-                            b1.target_dir = l_trans.forward();
-                            // info!("{} in swarm with", e1.to_bits());
-                            info!("b1 thrust {} {} b2 thrust", b1.thrust, b2.thrust);
-
-                            // b1.thrust = b2.thrust;
-                        } else {
-                            let diff = l_trans.translation - t1.translation;
-                            b1.target_dir = Dir3::new(diff.normalize_or(Vec3::Y)).unwrap();
-                            // info!("{} swarming with {}", e1.to_bits(), e2.to_bits());
-
-                            // b1.thrust = b2.thrust + 0.2;
-                            // this should work since the swarm up distance is less than 10
-                            // the idea is to accelerate faster when farther
+                    let (Ok(rb1), Ok(rb2)) = (body_query.get(e1), body_query.get(e2)) else {
+                        continue;
+                    };
+                    // a bot at rest has no heading to be out of line with
+                    let (v1, v2) = (
+                        rb1.velocity.normalize_or_zero(),
+                        rb2.velocity.normalize_or_zero(),
+                    );
+                    let aligned = v1 == Vec3::ZERO
+                        || v2 == Vec3::ZERO
+                        || v1.dot(v2).clamp(-1., 1.).acos() <= behavior.alignment_angle;
+                    let Ok([(_, mut b1, mut m1, mut l1, _, _), (_, mut b2, mut m2, mut l2, _, _)]) =
+                        query_bots.get_many_mut([e1, e2])
+                    else {
+                        continue;
+                    };
+                    // verify if this is necessary
+                    if m2.leader == Some(e1) {
+                        continue;
+                    }
+                    if aligned && l2.followers < l2.followers_limit {
+                        //swarm up only when coming from behind
+                        //right now swarming up the first bot it sees, not with the closest one
+
+                        //starting a swarm around a solo bot
+                        if l2.swarm_id == 0 {
+                            l2.swarm_id = id_pool.allocate();
+                            id_pool.subscribe(l2.swarm_id);
+                            m2.leader = Some(e2);
                         }
-                    } else {
-                        swarm_tracker.0.entry(e1.clone()).and_modify(|d| {
-                            d.state = SwarmBotState::Solo;
-                            d.swarm_id = 0;
-                            d.leader = None;
-                        });
-                        // error!("leader not present");
+                        l2.followers += 1;
+                        b2.state = SwarmBotState::InSwarm;
+
+                        // info!("{} swarming with {}", e1.to_bits(), e2.to_bits());
+                        b1.state = SwarmBotState::Swarming;
+                        m1.leader = Some(e2);
+                        l1.swarm_id = l2.swarm_id;
+                        id_pool.subscribe(l1.swarm_id);
+                        // b1 aims for the bot it swarmed up with
+                        b1.target_dir = Dir3::new((p2 - p1).normalize_or(Vec3::Y)).unwrap();
+                        break;
                     }
                 }
-                SwarmBotState::InSwarm => {
-                    // error prone code block
-                    // checking leader position wrt follower
-                    if let Some((e2, (t2, b2))) =
-                        bots_map.get_key_value(&swarm_tracker.0.get(&e1).unwrap().leader.unwrap())
-                    {
-                        if *e2 == e1 {
-                            continue;
-                        }
-                        // replace this with coerce logic
-                        if (t2.translation - t1.translation).length() > b1.swarm_spacing_max {
-                            if let Some(sd) = swarm_tracker.0.clone().get_mut(&e1) {
-                                if sd.followers == 0 {
-                                    swarm_tracker.0.get_mut(&e1).unwrap().state =
-                                        SwarmBotState::Solo;
-                                    swarm_tracker.0.get_mut(&e1).unwrap().leader = None;
-                                    swarm_tracker.1.unit_dec_subs(sd.swarm_id as usize);
-                                } else {
-                                    swarm_tracker.0.get_mut(&e1).unwrap().leader = Some(e1);
-                                }
-                            }
-                            continue;
-                        }
+            }
+            SwarmBotState::Swarming => {
+                let Some(leader) = leader.filter(|l| *l != e1) else {
+                    continue;
+                };
+                let Ok((_, mut b1, mut m1, mut l1, _, _)) = query_bots.get_mut(e1) else {
+                    continue;
+                };
+                if let Some(l) = grid.get(leader) {
+                    let target = slot.target(leader).unwrap_or(l.position);
+                    if (target - p1).length() <= behavior.separation_radius {
+                        b1.state = SwarmBotState::InSwarm;
+                        b1.target_dir = l.heading;
+                        // info!("{} in swarm with", e1.to_bits());
+
                         // b1.thrust = b2.thrust;
-                        b1.target_dir = b2.dir;
                     } else {
-                        if let Some(sd) = swarm_tracker.0.clone().get_mut(&e1) {
-                            if sd.followers == 0 {
-                                swarm_tracker.0.get_mut(&e1).unwrap().state = SwarmBotState::Solo;
-                                swarm_tracker.0.get_mut(&e1).unwrap().leader = None;
-                                swarm_tracker.1.unit_dec_subs(sd.swarm_id as usize);
-                            } else {
-                                swarm_tracker.0.get_mut(&e1).unwrap().leader = Some(e1);
-                            }
-                        }
-                    }
-                    // change the above block in future
+                        let diff = target - p1;
+                        b1.target_dir = Dir3::new(diff.normalize_or(Vec3::Y)).unwrap();
+                        // info!("{} swarming with {}", e1.to_bits(), e2.to_bits());
 
-                    // to check if the bot is getting too close to swarm bots
-                    for (e2, t2, _) in bots.iter() {
-                        if e1 == *e2 {
-                            continue;
-                        }
-                        let separation_vector = t2.translation - t1.translation;
-                        if separation_vector.length()
-                            < (b1.swarm_spacing_min + b1.swarm_spacing_max) / 2.
-                        {
-                            // b1.velocity = {
-                            //     b1.velocity
-                            //         + (-separation_vector.normalize_or_zero() * b1.repel_thrust)
-                            //             * time.delta_secs()
-                            // };
-                        }
+                        // b1.thrust = b2.thrust + 0.2;
+                        // this should work since the swarm up distance is less than 10
+                        // the idea is to accelerate faster when farther
                     }
+                } else {
+                    // error!("leader not present");
+                    go_solo(&mut b1, &mut m1, &mut l1, &mut id_pool);
                 }
-                _ => (),
             }
-        } else {
-            error!("Entity should be present!!");
+            SwarmBotState::InSwarm => {
+                let Ok((_, mut b1, mut m1, mut l1, _, _)) = query_bots.get_mut(e1) else {
+                    continue;
+                };
+                let Some(leader) = leader else {
+                    go_solo(&mut b1, &mut m1, &mut l1, &mut id_pool);
+                    continue;
+                };
+                if leader == e1 {
+                    // every follower left or was lost
+                    if l1.followers == 0 {
+                        go_solo(&mut b1, &mut m1, &mut l1, &mut id_pool);
+                    }
+                    continue;
+                }
+                // checking leader position wrt follower
+                let Some(l) = grid.get(leader) else {
+                    go_solo(&mut b1, &mut m1, &mut l1, &mut id_pool);
+                    continue;
+                };
+                let target = slot.target(leader).unwrap_or(l.position);
+                if (target - p1).length() > behavior.separation_radius {
+                    // fell out of its slot, catch up again until it drifts off far enough to split
+                    b1.state = SwarmBotState::Swarming;
+                    continue;
+                }
+                // b1.thrust = b2.thrust;
+                // fly along with the leader while holding the slot, crowding is left to avoidance
+                b1.target_dir = Dir3::new(l.heading.as_vec3() + (target - p1)).unwrap_or(l.heading);
+            }
+            _ => (),
         }
     }
 }
@@ -0,0 +1,91 @@
+use bevy::prelude::*;
+use std::f32::consts::PI;
+
+// boids weights and radii of a swarm, put on a SwarmPoint so every bot it
+// releases flies with the same profile. weights are accelerations
+#[derive(Component, Clone, Copy, Debug)]
+pub struct SwarmBehavior {
+    // closer than separation_min the velocity towards the other bot is cancelled,
+    // up to separation_radius the bots push away from each other.
+    // a follower within separation_radius of its leader counts as in the swarm
+    pub separation_min: f32,
+    pub separation_radius: f32,
+    pub separation_weight: f32,
+    // bots only swarm up when flying within this angle of each other,
+    // followers then match the velocity of their leader
+    pub alignment_angle: f32,
+    pub alignment_weight: f32,
+    // solo bots within this radius can swarm up, followers beyond
+    // separation_radius are pulled back to their leader
+    pub cohesion_radius: f32,
+    pub cohesion_weight: f32,
+    // followers further than this from their leader split off into a swarm of their own,
+    // has to be larger than cohesion_radius or swarms split as soon as they form
+    pub split_radius: f32,
+    // scales the thrust of solo bots and swarm leaders chasing a target
+    pub seek_weight: f32,
+    // obstacles closer than this push the bots away from their surface
+    pub avoidance_radius: f32,
+    pub avoidance_weight: f32,
+}
+
+impl Default for SwarmBehavior {
+    fn default() -> Self {
+        Self::checked(Self {
+            separation_min: 0.1,
+            separation_radius: 0.5,
+            separation_weight: 0.2,
+            alignment_angle: PI / 3.,
+            alignment_weight: 0.5,
+            cohesion_radius: 2.,
+            cohesion_weight: 0.3,
+            split_radius: 3.,
+            seek_weight: 1.,
+            avoidance_radius: 1.,
+            avoidance_weight: 2.,
+        })
+    }
+}
+
+impl SwarmBehavior {
+    // small fast swarms packed close around the leader
+    pub fn hornet() -> Self {
+        Self::checked(Self {
+            separation_radius: 0.3,
+            separation_weight: 0.4,
+            alignment_angle: PI / 2.,
+            alignment_weight: 1.5,
+            cohesion_radius: 1.5,
+            cohesion_weight: 0.8,
+            split_radius: 2.,
+            seek_weight: 1.5,
+            ..default()
+        })
+    }
+
+    // slow wide clouds that barely hold together
+    pub fn cloud() -> Self {
+        Self::checked(Self {
+            separation_radius: 1.2,
+            separation_weight: 0.1,
+            alignment_angle: PI / 4.,
+            alignment_weight: 0.1,
+            cohesion_radius: 4.,
+            cohesion_weight: 0.1,
+            split_radius: 6.,
+            seek_weight: 0.5,
+            avoidance_radius: 2.,
+            ..default()
+        })
+    }
+
+    fn checked(self) -> Self {
+        debug_assert!(
+            self.split_radius > self.cohesion_radius,
+            "split_radius {} has to be larger than cohesion_radius {}",
+            self.split_radius,
+            self.cohesion_radius
+        );
+        self
+    }
+}
@@ -0,0 +1,301 @@
+use super::missile::SwarmMissileShootEvent;
+use super::swarm::{SwarmBotMarker, SwarmGrid};
+use super::swarm_membership::{SwarmLeader, SwarmMember};
+use bevy::prelude::*;
+use std::f32::consts::TAU;
+
+// seconds a swarm holds its threat formation after swarm missiles are fired at it
+const THREAT_DURATION: f32 = 3.;
+// swarms led from this close to where the missiles converge brace as well
+const THREAT_RADIUS: f32 = 4.;
+// pi * (3 - sqrt 5), spreads points evenly over a sphere
+const GOLDEN_ANGLE: f32 = 2.399_963;
+const HELIX_SLOTS_PER_TURN: f32 = 6.;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FormationShape {
+    V,
+    Ring,
+    Sphere,
+    LineAbreast,
+    Helix,
+}
+
+// how the direct followers of a bot line up around it.
+// under threat the swarm takes the threat shape and spacing for a while
+#[derive(Component, Clone, Copy)]
+pub struct Formation {
+    pub shape: FormationShape,
+    pub spacing: f32,
+    pub threat_shape: FormationShape,
+    pub threat_spacing: f32,
+    threat_remaining: f32,
+}
+
+impl Default for Formation {
+    fn default() -> Self {
+        Self {
+            shape: FormationShape::V,
+            spacing: 0.6,
+            threat_shape: FormationShape::Sphere,
+            threat_spacing: 0.35,
+            threat_remaining: 0.,
+        }
+    }
+}
+
+impl Formation {
+    pub fn new(shape: FormationShape, spacing: f32) -> Self {
+        Self {
+            shape,
+            spacing,
+            ..default()
+        }
+    }
+
+    pub fn with_threat(mut self, shape: FormationShape, spacing: f32) -> Self {
+        self.threat_shape = shape;
+        self.threat_spacing = spacing;
+        self
+    }
+
+    pub fn threaten(&mut self) {
+        self.threat_remaining = THREAT_DURATION;
+    }
+
+    pub fn current(&self) -> (FormationShape, f32) {
+        if self.threat_remaining > 0. {
+            (self.threat_shape, self.threat_spacing)
+        } else {
+            (self.shape, self.spacing)
+        }
+    }
+
+    // offset of a slot from the leader in the leader's frame, forward is -z
+    pub fn slot_offset(shape: FormationShape, spacing: f32, index: u32, count: u32) -> Vec3 {
+        let i = index as f32;
+        let n = count.max(index + 1) as f32;
+        // alternating left and right, one rank further out every pair
+        let rank = (index / 2 + 1) as f32;
+        let side = if index % 2 == 0 { -1. } else { 1. };
+        match shape {
+            FormationShape::V => Vec3::new(side * rank, 0., rank) * spacing,
+            FormationShape::LineAbreast => Vec3::new(side * rank * spacing, 0., 0.),
+            FormationShape::Ring => {
+                let radius = (spacing * n / TAU).max(spacing);
+                let angle = TAU * i / n;
+                Vec3::new(radius * angle.cos(), radius * angle.sin(), spacing)
+            }
+            FormationShape::Sphere => {
+                let radius = (spacing * n.sqrt() / 2.).max(spacing);
+                let y = 1. - 2. * (i + 0.5) / n;
+                let r = (1. - y * y).sqrt();
+                let angle = i * GOLDEN_ANGLE;
+                Vec3::new(r * angle.cos(), y, r * angle.sin()) * radius
+            }
+            FormationShape::Helix => {
+                let angle = TAU * i / HELIX_SLOTS_PER_TURN;
+                Vec3::new(
+                    spacing * angle.cos(),
+                    spacing * angle.sin(),
+                    (i + 1.) * spacing / 2.,
+                )
+            }
+        }
+    }
+}
+
+// where the follower should be in its leader's formation, recomputed every tick
+#[derive(Component, Clone, Copy, Default)]
+pub struct FormationSlot {
+    pub leader: Option<Entity>,
+    pub position: Vec3,
+}
+
+impl FormationSlot {
+    // the slot is stale for a tick after the bot changed leaders
+    pub fn target(&self, leader: Entity) -> Option<Vec3> {
+        (self.leader == Some(leader)).then_some(self.position)
+    }
+}
+
+// every bot of a swarm leads its own followers, so the whole swarm tightens up
+pub(super) fn brace_for_missiles(
+    mut ev_swarm_missile: EventReader<SwarmMissileShootEvent>,
+    members: Query<&SwarmLeader, With<SwarmBotMarker>>,
+    mut formations: Query<
+        (
+            Entity,
+            &Transform,
+            &SwarmMember,
+            &SwarmLeader,
+            &mut Formation,
+        ),
+        With<SwarmBotMarker>,
+    >,
+    time: Res<Time>,
+    mut threatened: Local<Vec<u32>>,
+) {
+    threatened.clear();
+    for (_, _, _, _, mut formation) in formations.iter_mut() {
+        formation.threat_remaining = (formation.threat_remaining - time.delta_secs()).max(0.);
+    }
+    for SwarmMissileShootEvent { missile, .. } in ev_swarm_missile.read() {
+        if let Some(Ok(l)) = missile.target.map(|target| members.get(target)) {
+            threatened.push(l.swarm_id);
+        }
+        for (e, trans, member, l, _) in formations.iter() {
+            if member.is_leader(e)
+                && trans.translation.distance(missile.converge_point) <= THREAT_RADIUS
+            {
+                threatened.push(l.swarm_id);
+            }
+        }
+    }
+    if threatened.is_empty() {
+        return;
+    }
+    for (_, _, _, l, mut formation) in formations.iter_mut() {
+        if l.swarm_id != 0 && threatened.contains(&l.swarm_id) {
+            formation.threaten();
+        }
+    }
+}
+
+// followers are numbered in entity order so each keeps its slot while its leader's
+// followers stay the same, whatever order the query visits them in
+pub(super) fn assign_formation_slots(
+    mut followers: Query<(Entity, &SwarmMember, &mut FormationSlot), With<SwarmBotMarker>>,
+    leaders: Query<(&Formation, &SwarmLeader), With<SwarmBotMarker>>,
+    grid: Res<SwarmGrid>,
+    // leader and follower
+    mut pairs: Local<Vec<(Entity, Entity)>>,
+) {
+    pairs.clear();
+    for (e, member, mut slot) in followers.iter_mut() {
+        match member.leader.filter(|l| *l != e) {
+            Some(leader) => pairs.push((leader, e)),
+            None => *slot = FormationSlot::default(),
+        }
+    }
+    pairs.sort_unstable();
+    for swarm in pairs.chunk_by(|a, b| a.0 == b.0) {
+        let leader = swarm[0].0;
+        let (Some(l), Ok((formation, swarm_leader))) = (grid.get(leader), leaders.get(leader))
+        else {
+            continue;
+        };
+        let (shape, spacing) = formation.current();
+        let rotation = Transform::default().looking_to(l.heading, Dir3::Y).rotation;
+        for (index, &(_, e)) in swarm.iter().enumerate() {
+            let offset =
+                Formation::slot_offset(shape, spacing, index as u32, swarm_leader.followers);
+            if let Ok((_, _, mut slot)) = followers.get_mut(e) {
+                *slot = FormationSlot {
+                    leader: Some(leader),
+                    position: l.position + rotation * offset,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::movement::RigidBody;
+    use super::super::swarm::{rebuild_swarm_grid, SwarmBot};
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    const SHAPES: [FormationShape; 5] = [
+        FormationShape::V,
+        FormationShape::Ring,
+        FormationShape::Sphere,
+        FormationShape::LineAbreast,
+        FormationShape::Helix,
+    ];
+
+    #[test]
+    fn slot_offsets_are_distinct_and_off_the_leader() {
+        let count = 10;
+        for shape in SHAPES {
+            let offsets: Vec<Vec3> = (0..count)
+                .map(|i| Formation::slot_offset(shape, 0.6, i, count))
+                .collect();
+            for (i, a) in offsets.iter().enumerate() {
+                assert!(a.length() > 0.1, "{shape:?} slot {i} sits on the leader");
+                for (j, b) in offsets[i + 1..].iter().enumerate() {
+                    assert!(
+                        a.distance(*b) > 0.1,
+                        "{shape:?} slots {i} and {} overlap",
+                        i + j + 1
+                    );
+                }
+            }
+        }
+    }
+
+    fn slots(world: &mut World, bots: &[Entity]) -> Vec<FormationSlot> {
+        bots.iter()
+            .map(|&bot| *world.get::<FormationSlot>(bot).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn followers_keep_their_slots() {
+        let mut world = World::new();
+        world.init_resource::<SwarmGrid>();
+        let leader = world
+            .spawn((
+                SwarmBot::default(),
+                SwarmBotMarker,
+                RigidBody::new(1.),
+                Transform::default(),
+            ))
+            .id();
+        let followers: Vec<Entity> = (0..5)
+            .map(|i| {
+                world
+                    .spawn((
+                        SwarmBot::default(),
+                        SwarmBotMarker,
+                        RigidBody::new(1.),
+                        Transform::from_xyz(i as f32, 0., 1.),
+                        SwarmMember {
+                            leader: Some(leader),
+                        },
+                    ))
+                    .id()
+            })
+            .collect();
+        world.entity_mut(leader).insert((
+            SwarmMember {
+                leader: Some(leader),
+            },
+            SwarmLeader {
+                followers: 5,
+                swarm_id: 1,
+                ..default()
+            },
+        ));
+        world.run_system_once(rebuild_swarm_grid).unwrap();
+        world.run_system_once(assign_formation_slots).unwrap();
+        let before = slots(&mut world, &followers);
+
+        for (i, a) in before.iter().enumerate() {
+            assert_eq!(a.leader, Some(leader));
+            for b in before[i + 1..].iter() {
+                assert!(a.position.distance(b.position) > 0.1);
+            }
+        }
+
+        // moving a follower to another archetype changes the order the query visits them in
+        world.entity_mut(followers[1]).insert(Name::new("moved"));
+        world.run_system_once(assign_formation_slots).unwrap();
+        let after = slots(&mut world, &followers);
+
+        for (a, b) in before.iter().zip(after.iter()) {
+            assert_eq!(a.position, b.position);
+        }
+    }
+}
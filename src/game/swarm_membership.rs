@@ -0,0 +1,621 @@
+use super::swarm::{SwarmBot, SwarmBotMarker, SwarmBotState, SwarmGrid};
+use super::swarm_behavior::SwarmBehavior;
+use super::swarm_formation::FormationSlot;
+use bevy::ecs::component::ComponentId;
+use bevy::ecs::world::DeferredWorld;
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+// swarms are trees, every follower follows the bot it swarmed up with.
+// the root follows itself and solo bots follow no one
+#[derive(Component, Clone, Copy, Default)]
+#[component(on_remove = leave_swarm)]
+pub struct SwarmMember {
+    pub leader: Option<Entity>,
+}
+
+impl SwarmMember {
+    pub fn is_leader(&self, entity: Entity) -> bool {
+        self.leader == Some(entity)
+    }
+}
+
+// direct followers and the swarm id, 0 while solo, on every bot since any bot can start leading
+#[derive(Component, Clone, Copy)]
+pub struct SwarmLeader {
+    pub followers: u32,
+    pub followers_limit: u32,
+    pub swarm_id: u32,
+}
+
+impl Default for SwarmLeader {
+    fn default() -> Self {
+        Self {
+            followers: 0,
+            followers_limit: 10,
+            swarm_id: 0,
+        }
+    }
+}
+
+// hands out swarm ids and counts the members of each, ids start at 1 so 0 can
+// mean no swarm and an id is reused once its last member has left
+#[derive(Resource, Default)]
+pub struct SwarmIdPool {
+    members: Vec<u32>,
+    free: Vec<u32>,
+}
+
+impl SwarmIdPool {
+    pub fn allocate(&mut self) -> u32 {
+        if let Some(id) = self.free.pop() {
+            return id;
+        }
+        self.members.push(0);
+        self.members.len() as u32
+    }
+
+    pub fn subscribe(&mut self, id: u32) {
+        if let Some(count) = self.members.get_mut((id as usize).wrapping_sub(1)) {
+            *count += 1;
+        }
+    }
+
+    pub fn unsubscribe(&mut self, id: u32) {
+        let Some(count) = self.members.get_mut((id as usize).wrapping_sub(1)) else {
+            return;
+        };
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            self.free.push(id);
+        }
+    }
+
+    pub fn members(&self, id: u32) -> u32 {
+        self.members
+            .get((id as usize).wrapping_sub(1))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+// bots that died with followers left, with where they were and the bot they
+// followed, None for the root of the swarm
+#[derive(Resource, Default)]
+pub struct SwarmSuccession(HashMap<Entity, (Vec3, Option<Entity>)>);
+
+impl SwarmSuccession {
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+// a bot going away stops counting towards its swarm and its leader,
+// its own followers are left to elect_leaders
+fn leave_swarm(mut world: DeferredWorld, entity: Entity, _: ComponentId) {
+    let Some(leader) = world.get::<SwarmMember>(entity).and_then(|m| m.leader) else {
+        return;
+    };
+    let Some(&SwarmLeader {
+        followers,
+        swarm_id,
+        ..
+    }) = world.get::<SwarmLeader>(entity)
+    else {
+        return;
+    };
+    if let Some(mut id_pool) = world.get_resource_mut::<SwarmIdPool>() {
+        id_pool.unsubscribe(swarm_id);
+    }
+    let parent = (leader != entity).then_some(leader);
+    if let Some(mut l) = parent.and_then(|p| world.get_mut::<SwarmLeader>(p)) {
+        l.followers = l.followers.saturating_sub(1);
+    }
+    if followers > 0 {
+        let position = world
+            .get::<Transform>(entity)
+            .map_or(Vec3::ZERO, |t| t.translation);
+        if let Some(mut succession) = world.get_resource_mut::<SwarmSuccession>() {
+            succession.0.insert(entity, (position, parent));
+        }
+    }
+}
+
+pub fn go_solo(
+    bot: &mut SwarmBot,
+    member: &mut SwarmMember,
+    leader: &mut SwarmLeader,
+    id_pool: &mut SwarmIdPool,
+) {
+    id_pool.unsubscribe(leader.swarm_id);
+    bot.state = SwarmBotState::Solo;
+    member.leader = None;
+    leader.followers = 0;
+    leader.swarm_id = 0;
+}
+
+type MemberQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static Transform,
+        &'static mut SwarmBot,
+        &'static mut SwarmMember,
+        &'static mut SwarmLeader,
+        &'static SwarmBehavior,
+    ),
+    With<SwarmBotMarker>,
+>;
+
+// moves the bot and every bot following it, directly or not, over to swarm_id
+fn move_subtree(query: &mut MemberQuery, bot: Entity, swarm_id: u32, id_pool: &mut SwarmIdPool) {
+    let mut pending = vec![bot];
+    while let Some(e) = pending.pop() {
+        pending.extend(
+            query
+                .iter()
+                .filter(|(f, _, _, m, _, _)| *f != e && m.leader == Some(e))
+                .map(|(f, ..)| f),
+        );
+        let Ok((_, _, _, _, mut l, _)) = query.get_mut(e) else {
+            continue;
+        };
+        if l.swarm_id != swarm_id {
+            id_pool.unsubscribe(l.swarm_id);
+            id_pool.subscribe(swarm_id);
+            l.swarm_id = swarm_id;
+        }
+    }
+}
+
+// the follower closest to where a dead bot was takes its place, the other
+// followers follow the heir and the heir follows whoever the dead bot followed
+pub(super) fn elect_leaders(
+    mut succession: ResMut<SwarmSuccession>,
+    mut query: MemberQuery,
+    mut id_pool: ResMut<SwarmIdPool>,
+) {
+    for (fallen, (position, parent)) in succession.0.drain() {
+        let heir = query
+            .iter()
+            .filter(|(_, _, _, m, _, _)| m.leader == Some(fallen))
+            .min_by(|a, b| {
+                let da = a.1.translation.distance_squared(position);
+                let db = b.1.translation.distance_squared(position);
+                da.total_cmp(&db)
+            })
+            .map(|(e, ..)| e);
+        let Some(heir) = heir else {
+            continue;
+        };
+        let mut followers = 0;
+        for (e, _, _, mut m, _, _) in query.iter_mut() {
+            if e != heir && m.leader == Some(fallen) {
+                m.leader = Some(heir);
+                followers += 1;
+            }
+        }
+        // the parent may have died in the same tick
+        let alive_parent = parent.filter(|p| query.contains(*p));
+        let Ok((_, _, mut bot, mut m, mut l, _)) = query.get_mut(heir) else {
+            continue;
+        };
+        l.followers += followers;
+        if let Some(parent) = alive_parent {
+            m.leader = Some(parent);
+            bot.state = SwarmBotState::Swarming;
+            if let Ok((_, _, _, _, mut pl, _)) = query.get_mut(parent) {
+                pl.followers += 1;
+            }
+            continue;
+        }
+        if l.followers == 0 {
+            go_solo(&mut bot, &mut m, &mut l, &mut id_pool);
+            continue;
+        }
+        m.leader = Some(heir);
+        bot.state = SwarmBotState::InSwarm;
+        // cut off from the rest of its swarm, which keeps the old id
+        if parent.is_some() {
+            let swarm_id = id_pool.allocate();
+            move_subtree(&mut query, heir, swarm_id, &mut id_pool);
+        }
+    }
+}
+
+// followers that drifted further than split_radius from their slot break off,
+// taking the bots following them along as a new swarm
+pub(super) fn split_swarms(
+    mut query: MemberQuery,
+    slots: Query<&FormationSlot>,
+    grid: Res<SwarmGrid>,
+    mut id_pool: ResMut<SwarmIdPool>,
+    mut drifted: Local<Vec<(Entity, Entity)>>,
+) {
+    drifted.clear();
+    for (e, trans, _, m, _, behavior) in query.iter() {
+        let Some(leader) = m.leader.filter(|l| *l != e) else {
+            continue;
+        };
+        let Some(l) = grid.get(leader) else {
+            continue;
+        };
+        let target = slots
+            .get(e)
+            .ok()
+            .and_then(|slot| slot.target(leader))
+            .unwrap_or(l.position);
+        if target.distance(trans.translation) > behavior.split_radius {
+            drifted.push((e, leader));
+        }
+    }
+    for &(e, old_leader) in drifted.iter() {
+        if let Ok((_, _, _, _, mut l, _)) = query.get_mut(old_leader) {
+            l.followers = l.followers.saturating_sub(1);
+        }
+        let Ok((_, _, mut bot, mut m, mut l, _)) = query.get_mut(e) else {
+            continue;
+        };
+        if l.followers == 0 {
+            go_solo(&mut bot, &mut m, &mut l, &mut id_pool);
+            continue;
+        }
+        m.leader = Some(e);
+        bot.state = SwarmBotState::InSwarm;
+        let swarm_id = id_pool.allocate();
+        move_subtree(&mut query, e, swarm_id, &mut id_pool);
+    }
+}
+
+// roots within cohesion_radius of each other merge, the root of the smaller
+// swarm follows the root of the bigger one as long as it has room for it
+pub(super) fn merge_swarms(
+    mut query: MemberQuery,
+    grid: Res<SwarmGrid>,
+    mut id_pool: ResMut<SwarmIdPool>,
+    // absorbed root, absorbing root
+    mut merges: Local<Vec<(Entity, Entity)>>,
+) {
+    merges.clear();
+    for (e, trans, _, m, l, behavior) in query.iter() {
+        if !m.is_leader(e) {
+            continue;
+        }
+        let size = id_pool.members(l.swarm_id);
+        for (other, _) in grid.neighbours(trans.translation, behavior.cohesion_radius) {
+            if other == e {
+                continue;
+            }
+            let Ok((_, _, _, om, ol, _)) = query.get(other) else {
+                continue;
+            };
+            // ties are broken by entity so only one of the pair absorbs the other
+            if !om.is_leader(other) || (size, e) > (id_pool.members(ol.swarm_id), other) {
+                continue;
+            }
+            // a swarm is either absorbed or absorbing within a tick
+            if merges.iter().any(|&(a, b)| a == other || b == e) {
+                continue;
+            }
+            let pending = merges.iter().filter(|(_, b)| *b == other).count() as u32;
+            if ol.followers + pending >= ol.followers_limit {
+                continue;
+            }
+            merges.push((e, other));
+            break;
+        }
+    }
+    for &(absorbed, into) in merges.iter() {
+        let Ok((_, _, _, _, mut into_l, _)) = query.get_mut(into) else {
+            continue;
+        };
+        into_l.followers += 1;
+        let swarm_id = into_l.swarm_id;
+        let Ok((_, _, mut bot, mut m, _, _)) = query.get_mut(absorbed) else {
+            continue;
+        };
+        m.leader = Some(into);
+        bot.state = SwarmBotState::Swarming;
+        move_subtree(&mut query, absorbed, swarm_id, &mut id_pool);
+    }
+}
+
+// what has to hold between ticks: solo bots have no swarm, followers follow
+// a bot of the same swarm, every bot counts its direct followers, every swarm
+// has a single root every member leads back to and the pool counts every member
+pub fn swarm_invariant_violation<'a>(
+    bots: impl Iterator<Item = (Entity, &'a SwarmMember, &'a SwarmLeader)>,
+    id_pool: &SwarmIdPool,
+) -> Option<String> {
+    let bots: HashMap<Entity, (SwarmMember, SwarmLeader)> =
+        bots.map(|(e, m, l)| (e, (*m, *l))).collect();
+    let mut followers = HashMap::<Entity, u32>::new();
+    let mut members = HashMap::<u32, u32>::new();
+    let mut roots = HashMap::<u32, u32>::new();
+    for (e, (m, l)) in bots.iter() {
+        let Some(leader) = m.leader else {
+            if l.swarm_id != 0 {
+                return Some(format!("solo bot {e} still in swarm {}", l.swarm_id));
+            }
+            continue;
+        };
+        if l.swarm_id == 0 {
+            return Some(format!("bot {e} follows {leader} without a swarm id"));
+        }
+        *members.entry(l.swarm_id).or_default() += 1;
+        if leader == *e {
+            *roots.entry(l.swarm_id).or_default() += 1;
+            continue;
+        }
+        let Some((lm, ll)) = bots.get(&leader) else {
+            return Some(format!("bot {e} follows missing leader {leader}"));
+        };
+        if lm.leader.is_none() || ll.swarm_id != l.swarm_id {
+            return Some(format!(
+                "bot {e} follows {leader} which is not in its swarm"
+            ));
+        }
+        *followers.entry(leader).or_default() += 1;
+    }
+    for (e, (_, l)) in bots.iter() {
+        let count = followers.get(e).copied().unwrap_or(0);
+        if count != l.followers {
+            return Some(format!(
+                "bot {e} counts {} followers but has {count}",
+                l.followers
+            ));
+        }
+    }
+    for (e, (m, _)) in bots.iter() {
+        let mut current = (*e, *m);
+        for _ in 0..bots.len() {
+            match current.1.leader {
+                Some(leader) if leader != current.0 => current = (leader, bots[&leader].0),
+                _ => break,
+            }
+        }
+        if current.1.leader.is_some_and(|leader| leader != current.0) {
+            return Some(format!("bot {e} never leads back to the root of its swarm"));
+        }
+    }
+    for (id, count) in members {
+        if roots.get(&id).copied().unwrap_or(0) != 1 {
+            return Some(format!("swarm {id} does not have a single root"));
+        }
+        if id_pool.members(id) != count {
+            return Some(format!(
+                "swarm {id} has {count} members but the pool counts {}",
+                id_pool.members(id)
+            ));
+        }
+    }
+    None
+}
+
+#[cfg(debug_assertions)]
+pub(super) fn verify_swarms(
+    query: Query<(Entity, &SwarmMember, &SwarmLeader), With<SwarmBotMarker>>,
+    id_pool: Res<SwarmIdPool>,
+) {
+    if let Some(violation) = swarm_invariant_violation(query.iter(), &id_pool) {
+        error!("swarm invariant broken: {violation}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::movement::RigidBody;
+    use super::super::swarm::rebuild_swarm_grid;
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    fn world() -> World {
+        let mut world = World::new();
+        world.init_resource::<SwarmIdPool>();
+        world.init_resource::<SwarmSuccession>();
+        world.init_resource::<SwarmGrid>();
+        world
+    }
+
+    // a swarm of bots at the given positions, each following the bot at the
+    // given index, the bot following itself is the root
+    fn spawn_swarm(world: &mut World, bots: &[(Vec3, usize)]) -> Vec<Entity> {
+        let entities: Vec<Entity> = bots
+            .iter()
+            .map(|(p, _)| {
+                world
+                    .spawn((
+                        SwarmBot::default(),
+                        SwarmBotMarker,
+                        RigidBody::new(1.),
+                        Transform::from_translation(*p),
+                    ))
+                    .id()
+            })
+            .collect();
+        let swarm_id = world.resource_mut::<SwarmIdPool>().allocate();
+        for (i, &bot) in entities.iter().enumerate() {
+            world.resource_mut::<SwarmIdPool>().subscribe(swarm_id);
+            let followers = bots
+                .iter()
+                .enumerate()
+                .filter(|&(j, &(_, leader))| j != i && leader == i)
+                .count() as u32;
+            let mut entity = world.entity_mut(bot);
+            entity.get_mut::<SwarmBot>().unwrap().state = SwarmBotState::InSwarm;
+            entity.get_mut::<SwarmMember>().unwrap().leader = Some(entities[bots[i].1]);
+            *entity.get_mut::<SwarmLeader>().unwrap() = SwarmLeader {
+                followers,
+                swarm_id,
+                ..default()
+            };
+        }
+        entities
+    }
+
+    fn violation(world: &mut World) -> Option<String> {
+        let mut query =
+            world.query_filtered::<(Entity, &SwarmMember, &SwarmLeader), With<SwarmBotMarker>>();
+        swarm_invariant_violation(query.iter(world), world.resource::<SwarmIdPool>())
+    }
+
+    fn leader(world: &World, bot: Entity) -> Option<Entity> {
+        world.get::<SwarmMember>(bot).unwrap().leader
+    }
+
+    fn swarm_id(world: &World, bot: Entity) -> u32 {
+        world.get::<SwarmLeader>(bot).unwrap().swarm_id
+    }
+
+    fn followers(world: &World, bot: Entity) -> u32 {
+        world.get::<SwarmLeader>(bot).unwrap().followers
+    }
+
+    #[test]
+    fn closest_follower_takes_over_the_swarm() {
+        let mut world = world();
+        let bots = spawn_swarm(
+            &mut world,
+            &[
+                (Vec3::ZERO, 0),
+                (Vec3::X, 0),
+                (Vec3::X * 0.5, 0),
+                (Vec3::NEG_X * 2., 0),
+                (Vec3::X * 1.5, 1),
+            ],
+        );
+        assert_eq!(violation(&mut world), None);
+
+        world.despawn(bots[0]);
+        world.run_system_once(elect_leaders).unwrap();
+
+        let heir = bots[2];
+        assert_eq!(leader(&world, heir), Some(heir));
+        assert_eq!(leader(&world, bots[1]), Some(heir));
+        assert_eq!(leader(&world, bots[3]), Some(heir));
+        // the rest of the tree stays as it was
+        assert_eq!(leader(&world, bots[4]), Some(bots[1]));
+        assert_eq!(followers(&world, heir), 2);
+        assert_eq!(violation(&mut world), None);
+    }
+
+    #[test]
+    fn heir_of_a_follower_takes_its_place() {
+        let mut world = world();
+        let bots = spawn_swarm(
+            &mut world,
+            &[
+                (Vec3::ZERO, 0),
+                (Vec3::X, 0),
+                (Vec3::X * 2., 1),
+                (Vec3::X * 1.5, 1),
+            ],
+        );
+        let id = swarm_id(&world, bots[0]);
+
+        world.despawn(bots[1]);
+        world.run_system_once(elect_leaders).unwrap();
+
+        let heir = bots[3];
+        assert_eq!(leader(&world, heir), Some(bots[0]));
+        assert_eq!(leader(&world, bots[2]), Some(heir));
+        assert_eq!(followers(&world, bots[0]), 1);
+        assert_eq!(followers(&world, heir), 1);
+        assert!([0, 2, 3].iter().all(|&i| swarm_id(&world, bots[i]) == id));
+        assert_eq!(violation(&mut world), None);
+    }
+
+    #[test]
+    fn last_follower_goes_solo() {
+        let mut world = world();
+        let bots = spawn_swarm(&mut world, &[(Vec3::ZERO, 0), (Vec3::X, 0)]);
+        let id = swarm_id(&world, bots[0]);
+
+        world.despawn(bots[0]);
+        world.run_system_once(elect_leaders).unwrap();
+
+        assert_eq!(leader(&world, bots[1]), None);
+        assert_eq!(swarm_id(&world, bots[1]), 0);
+        assert_eq!(world.resource::<SwarmIdPool>().members(id), 0);
+        assert_eq!(violation(&mut world), None);
+    }
+
+    #[test]
+    fn drifted_follower_splits_off_with_its_followers() {
+        let mut world = world();
+        let bots = spawn_swarm(
+            &mut world,
+            &[
+                (Vec3::ZERO, 0),
+                (Vec3::X * 0.5, 0),
+                (Vec3::X * 10., 0),
+                (Vec3::X * 10.5, 2),
+                (Vec3::NEG_X * 10., 0),
+            ],
+        );
+        world.run_system_once(rebuild_swarm_grid).unwrap();
+        world.run_system_once(split_swarms).unwrap();
+
+        assert_eq!(leader(&world, bots[1]), Some(bots[0]));
+        assert_eq!(followers(&world, bots[0]), 1);
+        assert_eq!(leader(&world, bots[2]), Some(bots[2]));
+        assert_eq!(leader(&world, bots[3]), Some(bots[2]));
+        assert_eq!(followers(&world, bots[2]), 1);
+        assert_ne!(swarm_id(&world, bots[2]), swarm_id(&world, bots[0]));
+        assert_eq!(swarm_id(&world, bots[3]), swarm_id(&world, bots[2]));
+        // nobody to take along
+        assert_eq!(leader(&world, bots[4]), None);
+        assert_eq!(violation(&mut world), None);
+    }
+
+    #[test]
+    fn smaller_swarm_merges_into_bigger_one() {
+        let mut world = world();
+        let big = spawn_swarm(
+            &mut world,
+            &[
+                (Vec3::ZERO, 0),
+                (Vec3::new(-0.5, 0., 0.5), 0),
+                (Vec3::new(0.5, 0., 0.5), 0),
+            ],
+        );
+        let small = spawn_swarm(&mut world, &[(Vec3::X, 0), (Vec3::new(1., 0., 0.5), 0)]);
+        let (big_id, small_id) = (swarm_id(&world, big[0]), swarm_id(&world, small[0]));
+
+        world.run_system_once(rebuild_swarm_grid).unwrap();
+        world.run_system_once(merge_swarms).unwrap();
+
+        assert_eq!(leader(&world, small[0]), Some(big[0]));
+        assert_eq!(leader(&world, small[1]), Some(small[0]));
+        assert_eq!(followers(&world, big[0]), 3);
+        assert!(small.iter().all(|&bot| swarm_id(&world, bot) == big_id));
+        assert_eq!(world.resource::<SwarmIdPool>().members(small_id), 0);
+        assert_eq!(violation(&mut world), None);
+    }
+
+    #[test]
+    fn id_pool_reuses_freed_ids_and_grows() {
+        let mut pool = SwarmIdPool::default();
+        let (a, b) = (pool.allocate(), pool.allocate());
+        assert_eq!((a, b), (1, 2));
+        pool.subscribe(a);
+        pool.subscribe(a);
+        pool.subscribe(b);
+
+        // still one member left
+        pool.unsubscribe(a);
+        assert_eq!(pool.members(a), 1);
+        assert_eq!(pool.allocate(), 3);
+
+        pool.unsubscribe(a);
+        assert_eq!(pool.members(a), 0);
+        assert_eq!(pool.allocate(), a);
+        assert_eq!(pool.allocate(), 4);
+        assert_eq!(pool.members(b), 1);
+
+        // 0 is no swarm and never counted
+        pool.subscribe(0);
+        assert_eq!(pool.members(0), 0);
+    }
+}
@@ -1,7 +1,8 @@
-use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
-use super::collider;
+use super::damage::{DamageType, DestroyedWriter, EntityDestroyed};
+use super::faction::Faction;
+use super::movement::TransformInterpolation;
 use super::spaceship::Health;
 use super::{collider::*, GameObjectMarker};
 use crate::sets::*;
@@ -9,6 +10,9 @@ use crate::states::*;
 use bevy::prelude::*;
 
 const DEFAULT_BULLET_RANGE: f32 = 20.;
+// bullets are thin streaks along their direction of travel
+const BULLET_RADIUS: f32 = 0.01;
+const BULLET_HALF_LENGTH: f32 = 0.1;
 
 //* Add code for input */
 // #[derive(Component)]
@@ -40,6 +44,7 @@ pub struct TurretBundle {
     pub overheat_limit: f32,
     pub overheat: bool,
     pub bullet_inertial_velocity: Vec3,
+    pub bullet_layers: CollisionLayers,
 }
 
 #[derive(Event)]
@@ -65,6 +70,7 @@ impl Default for TurretBundle {
             cooldown: 0.,
             cooldown_time: 3.,
             overheat: false,
+            bullet_layers: CollisionLayers::player_projectile(),
         }
     }
 }
@@ -101,13 +107,8 @@ impl Plugin for TurretPlugin {
             .add_systems(OnEnter(InGameStates::Setup), setup.in_set(SetupSet::InGame))
             // .add_systems(Update, shoot_turret)
             .add_systems(
-                Update,
-                (
-                    bullet_travel,
-                    collider::collision_response::<BulletMarker>,
-                    despawn_bullet,
-                )
-                    .in_set(UpdateSet::InGame),
+                FixedUpdate,
+                (bullet_travel, expire_bullets).in_set(UpdateSet::InGame),
             );
     }
 }
@@ -132,14 +133,17 @@ fn bullet_travel(
     }
 }
 
-fn despawn_bullet(
-    mut commands: Commands,
-    query: Query<(Entity, &Bullet, &Health), With<BulletMarker>>,
+fn expire_bullets(
+    query: Query<(Entity, &Bullet, &Transform), With<BulletMarker>>,
+    mut ev_destroyed: DestroyedWriter,
 ) {
-    for (entity, bullet, health) in query.iter() {
-        if bullet.distance_covered > DEFAULT_BULLET_RANGE || health.0 <= 0. {
-            info!("bulllet");
-            commands.entity(entity).despawn_recursive();
+    for (entity, bullet, trans) in query.iter() {
+        if bullet.distance_covered > DEFAULT_BULLET_RANGE {
+            ev_destroyed.send(EntityDestroyed {
+                entity,
+                killer: None,
+                position: trans.translation,
+            });
         }
     }
 }
@@ -161,6 +165,7 @@ pub fn shoot_turret<T: Component>(
     mut timer: ResMut<FireRateTimer>,
     time: Res<Time>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+    faction_query: Query<&Faction>,
 ) {
     for (mut tur, gt) in query.iter_mut() {
         match tur.0.shooting {
@@ -170,13 +175,14 @@ pub fn shoot_turret<T: Component>(
                         continue;
                     }
                     tur.0.cooldown += 0.08;
-                    commands.spawn((
+                    let mut bullet_entity = commands.spawn((
                         GameObjectMarker,
                         SceneRoot(bullet.handle.clone()),
                         Transform::from_translation(gt.translation().clone())
                             .with_scale(Vec3::ONE * tur.0.bullet_size.clone())
                             .with_rotation(gt.rotation().clone()),
                         BulletMarker,
+                        TransformInterpolation::default(),
                         Bullet {
                             speed: tur.0.speed.clone(),
                             direction: gt.forward(),
@@ -189,17 +195,29 @@ pub fn shoot_turret<T: Component>(
                             ..default()
                         })),
                         ColliderMarker,
+                        SweptCollider {
+                            previous_center: gt.translation(),
+                        },
                         ColliderInfo {
-                            collider_type: ColliderType::Point,
-                            collider: Arc::new(RwLock::new(PointCollider { center: Vec3::ZERO })),
-                            // ikik not checking for none value cause it won't happen;source: trust me bro
-                            immune_to: Some(Vec::from([tur.0.shooter.unwrap()])),
+                            shape: ColliderShape::Capsule(CapsuleCollider {
+                                radius: BULLET_RADIUS,
+                                half_length: BULLET_HALF_LENGTH,
+                                center: Vec3::ZERO,
+                                rotation: Quat::IDENTITY,
+                            }),
+                            layers: tur.0.bullet_layers,
                         },
                         CollisionDamage {
                             damage: 20.,
+                            kind: DamageType::Energy,
                             from: tur.0.shooter,
+                            mode: DamageMode::OnEnter,
                         },
                     ));
+                    // the shooter may be gone by the time the bullet hits
+                    if let Some(Ok(faction)) = tur.0.shooter.map(|s| faction_query.get(s)) {
+                        bullet_entity.insert(*faction);
+                    }
                     if tur.0.cooldown >= tur.0.overheat_limit {
                         tur.0.overheat = true;
                         tur.0.cooldown = tur.0.cooldown_time;